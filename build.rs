@@ -1,49 +1,133 @@
-use std::{fs::OpenOptions, io::{Read, Write}, path::PathBuf};
+use std::{fs::{File, OpenOptions}, io::{BufWriter, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+/// Must match kernel/src/initramfs.rs
+const RAMDISK_MAGIC: [u8; 4] = *b"EVRD";
+const RAMDISK_VERSION: u32 = 2;
+/// Magic, version, crc32 of everything after the header, padding and file count (u64)
+const RAMDISK_HEADER_LEN: usize = 4 + 4 + 4 + 4 + size_of::<u64>();
+/// Name offset, name length and file length, each a u64
+const RAMDISK_ENTRY_FIELD_LEN: usize = size_of::<u64>();
+const RAMDISK_ENTRY_LEN: usize = 3 * RAMDISK_ENTRY_FIELD_LEN;
+
+/// Table driven IEEE CRC32 (same as zlib), fast enough for large ramdisks
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// Computes the CRC32 of everything written through it
+struct CrcWriter<W: Write> {
+    inner: W,
+    /// Inverted running state
+    state: u32,
+}
+
+impl<W: Write> CrcWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, state: !0 }
+    }
+
+    fn crc(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.state = buf[..written].iter().fold(self.state, |crc, &byte| (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-fn make_static_disk_from_folder<'a>(folder: impl Into<&'a str>) -> Box<[u8]> {
+/// Collects (relative path with '/' separators, full path) of all files below folder
+fn collect_files(folder: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) {
+    let entries = folder.read_dir().expect(format!("Could not read folder {}", folder.display()).as_str());
+
+    for entry in entries {
+        let entry = entry.unwrap_or_else(|err| panic!("Could not use DirEntry due to {}", err));
+        let name = entry.file_name().into_string().unwrap_or_else(|name| panic!("Invalid file name {:?}", name));
+        let name = match prefix {
+            "" => name,
+            prefix => format!("{}/{}", prefix, name),
+        };
+
+        match entry.path().is_dir() {
+            true => collect_files(&entry.path(), &name, files),
+            false => files.push((name, entry.path())),
+        }
+    }
+}
+
+/// Packs every file below folder into output, file contents are streamed so they never all sit in memory
+fn make_static_disk_from_folder<'a>(folder: impl Into<&'a str>, output: &Path) {
     let folder_name = folder.into();
 
-    let folder = PathBuf::from(folder_name).read_dir().expect(format!("Passed invalid folder {} to make_static_disk_from_folder", folder_name).as_str());
+    assert!(PathBuf::from(folder_name).is_dir(), "Passed invalid folder {} to make_static_disk_from_folder", folder_name);
 
     println!("cargo:rerun-if-changed={}", folder_name);
 
-    let files = folder.map(|file| {
-        let name = match file {
-            Ok(file) => match file.path().is_dir() {
-                true => "evos_fun_impl_no_file".to_string().into(),
-                false => file.file_name(),
-            },
-            Err(err) => panic!("Could not use DirEntry due to {}", err)
-        };
-        name.clone().into_string().expect(format!("Invalid file name {:?}", name).as_str())
-    }).filter(|s| s != "evos_fun_impl_no_file");
+    let mut files = Vec::new();
+    collect_files(&PathBuf::from(folder_name), "", &mut files);
+    // Stable layout between builds
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
 
-    let mut file_count = 0;
+    if let Some(pair) = files.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        panic!("Duplicate ramdisk file name {} ({} and {})", pair[0].0, pair[0].1.display(), pair[1].1.display());
+    }
 
-    let all = files.map(|file| {
-        let mut buf = Vec::new();
-        OpenOptions::new().read(true).open(PathBuf::from(folder_name).join(file.as_str())).expect("Could not open file?").read_to_end(&mut buf).expect("Could not read file!");
-        file_count += 1;
-        (file, buf)
+    // Layout first, so the table can be written before any contents
+    let sizes = files.iter().map(|(_, path)| {
+        path.metadata().unwrap_or_else(|err| panic!("Could not stat {} due to {}", path.display(), err)).len() as usize
     }).collect::<Vec<_>>();
 
-    assert!(all.len() == file_count);
+    let file_count = files.len();
+
+    let mut disk = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(output).expect("Could not create ramdisk"));
 
-    let total_len = all.iter().fold(0, |old, (name, content)| old + name.len() + content.len()) + size_of::<usize>() + size_of::<usize>() * file_count * 3;
+    disk.write_all(&RAMDISK_MAGIC).unwrap();
+    disk.write_all(&RAMDISK_VERSION.to_le_bytes()).unwrap();
+    // Crc is filled in last
+    disk.write_all(&[0; 4 + 4]).unwrap();
+    disk.write_all(&(file_count as u64).to_le_bytes()).unwrap();
 
-    let mut end_file = vec![0u8; total_len];
+    // The crc covers everything after the header
+    let mut disk = CrcWriter::new(disk);
+
+    let mut offset = RAMDISK_HEADER_LEN + RAMDISK_ENTRY_LEN * file_count;
+    for ((name, _), &size) in files.iter().zip(&sizes) {
+        disk.write_all(&(offset as u64).to_le_bytes()).unwrap();
+        disk.write_all(&(name.len() as u64).to_le_bytes()).unwrap();
+        disk.write_all(&(size as u64).to_le_bytes()).unwrap();
+        offset += name.len() + size;
+    }
 
-    let mut name_offset = end_file.as_mut_slice().write(&file_count.to_le_bytes()).unwrap();
-    let mut offset = name_offset + size_of::<usize>() * file_count * 3;
-    for (name, file) in all {
-        name_offset += (&mut end_file.as_mut_slice()[name_offset..]).write(&offset.to_le_bytes()).unwrap();
-        name_offset += (&mut end_file.as_mut_slice()[name_offset..]).write(&name.len().to_le_bytes()).unwrap();
-        name_offset += (&mut end_file.as_mut_slice()[name_offset..]).write(&file.len().to_le_bytes()).unwrap();
-        offset += (&mut end_file.as_mut_slice()[offset..]).write(name.as_bytes()).unwrap();
-        offset += (&mut end_file.as_mut_slice()[offset..]).write(file.as_slice()).unwrap();
+    for ((name, path), &size) in files.iter().zip(&sizes) {
+        disk.write_all(name.as_bytes()).unwrap();
+        let mut file = File::open(path).expect("Could not open file?");
+        let copied = std::io::copy(&mut file, &mut disk).expect("Could not read file!");
+        assert_eq!(copied as usize, size, "File {} changed size while packing the ramdisk", path.display());
     }
 
-    end_file.into_boxed_slice()
+    let crc = disk.crc();
+    let mut disk = disk.inner.into_inner().expect("Could not write ramdisk");
+    disk.seek(SeekFrom::Start(8)).unwrap();
+    disk.write_all(&crc.to_le_bytes()).expect("Could not write ramdisk");
 }
 
 fn main() {
@@ -52,9 +136,8 @@ fn main() {
     let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_EVKRNL_evkrnl").unwrap());
 
-    let file = make_static_disk_from_folder("ramdisk");
     let ramdisk_name = out_dir.join("ramdisk");
-    OpenOptions::new().write(true).create(true).open(&ramdisk_name).unwrap().write_all(&file).expect("Could not write ramdisk");
+    make_static_disk_from_folder("ramdisk", &ramdisk_name);
 
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel).set_ramdisk(&ramdisk_name).create_disk_image(&uefi_path).unwrap();
@@ -16,17 +16,24 @@ macro_rules! conf_dep {
 #[derive(Debug, Deserialize)]
 struct FrameBufferConfig {
     font: String,
+    history_lines: usize,
 }
 
 impl FrameBufferConfig {
     fn write_to_file(self, file: &mut BufWriter<std::fs::File>) -> Result<(), Box<dyn Error>> {
         writeln!(file, "{}", match self.font.as_str() {
-            "basic8x8" => "pub type Font = crate::text::font::Basic8x8;",
-            "ter16x32" => "pub type Font = crate::text::font::Ter16x32;",
-            "sun8x16" => "pub type Font = crate::text::font::Sun8x16;",
+            "basic8x8" => "pub const FONT: crate::text::font::FontKind = crate::text::font::FontKind::Basic8x8;",
+            "ter16x32" => "pub const FONT: crate::text::font::FontKind = crate::text::font::FontKind::Ter16x32;",
+            "sun8x16" => "pub const FONT: crate::text::font::FontKind = crate::text::font::FontKind::Sun8x16;",
             font => Err(format!("config::framebuffer::font: Invalid font {}", font))?
         })?;
 
+        if self.history_lines == 0 {
+            Err("config::framebuffer::history_lines: Must be at least 1")?
+        }
+
+        writeln!(file, "pub const HISTORY_LINES: usize = {};", self.history_lines)?;
+
         Ok(())
     }
 }
@@ -63,12 +70,60 @@ impl KeyboardConfig {
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct MemConfig {
+    memtest: bool,
+    memtest_percent: u8,
+    virt_pages_max: usize,
+    zero_user_pages: bool,
+    zero_freed_frames: bool,
+}
+
+impl MemConfig {
+    fn write_to_file(self, file: &mut BufWriter<std::fs::File>) -> Result<(), Box<dyn Error>> {
+        if !(1..=100).contains(&self.memtest_percent) {
+            Err(format!("config::mem::memtest_percent: Invalid percentage {}", self.memtest_percent))?
+        }
+
+        if self.virt_pages_max == 0 {
+            Err("config::mem::virt_pages_max: Must be at least 1")?
+        }
+
+        writeln!(file, "pub const MEMTEST: bool = {};", self.memtest)?;
+        writeln!(file, "pub const MEMTEST_STRIDE: usize = {};", 100usize.div_ceil(self.memtest_percent as usize))?;
+        writeln!(file, "pub const VIRT_PAGES_MAX: usize = {};", self.virt_pages_max)?;
+        writeln!(file, "pub const ZERO_USER_PAGES: bool = {};", self.zero_user_pages)?;
+        writeln!(file, "pub const ZERO_FREED_FRAMES: bool = {};", self.zero_freed_frames)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QemuConfig {
+    exit_on_panic: bool,
+}
+
+impl QemuConfig {
+    fn write_to_file(self, _file: &mut BufWriter<std::fs::File>) -> Result<(), Box<dyn Error>> {
+        println!("cargo::rustc-check-cfg=cfg(qemu_exit)");
+        if self.exit_on_panic {
+            println!("cargo::rustc-cfg=qemu_exit");
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct KernelConfig {
     framebuffer: FrameBufferConfig,
     modules: ModulesConfig,
     keyboard: KeyboardConfig,
+    mem: MemConfig,
+    qemu: QemuConfig,
     log_level: String,
+    log_timestamp: String,
 }
 
 impl KernelConfig {
@@ -79,6 +134,8 @@ impl KernelConfig {
         conf_dep!(self, file, framebuffer);
         conf_dep!(self, file, modules);
         conf_dep!(self, file, keyboard);
+        conf_dep!(self, file, mem);
+        conf_dep!(self, file, qemu);
 
         writeln!(file, "#[derive(PartialOrd, Ord, PartialEq, Eq)]\npub enum LogLevel {{\n    Critical,Error,Warn,Info,Debug\n}}")?;
         writeln!(file, "pub const LOG_LEVEL: LogLevel = {};", match self.log_level.as_str() {
@@ -90,6 +147,14 @@ impl KernelConfig {
             _ => Err(format!("config::LOG_LEVEL: Invalid level {}", self.log_level))?
         })?;
 
+        writeln!(file, "#[derive(Clone, Copy, Debug, PartialEq, Eq)]\npub enum LogTimestamp {{\n    None,Boot,Wall\n}}")?;
+        writeln!(file, "pub const LOG_TIMESTAMP: LogTimestamp = {};", match self.log_timestamp.as_str() {
+            "none" => "LogTimestamp::None",
+            "boot" => "LogTimestamp::Boot",
+            "wall" => "LogTimestamp::Wall",
+            _ => Err(format!("config::LOG_TIMESTAMP: Invalid format {}", self.log_timestamp))?
+        })?;
+
         Ok(())
     }
 }
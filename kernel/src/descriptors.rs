@@ -1,46 +1,96 @@
 use spin::{Mutex, MutexGuard};
-use x86_64::{instructions::tables::load_tss, registers::segmentation::{Segment, CS, DS, ES, FS, GS, SS}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}, PrivilegeLevel, VirtAddr};
+use x86_64::{instructions::{interrupts::without_interrupts, tables::load_tss}, registers::segmentation::{Segment, CS, DS, ES, FS, GS, SS}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}, PrivilegeLevel, VirtAddr};
 
-use crate::mem::STACK_SIZE;
+use crate::{debug, mem::{virt::{VirtFrame, VirtPages}, GuardedStack}};
 
 static GLOBAL: Mutex<GlobalDescriptorTable> = Mutex::new(GlobalDescriptorTable::new());
 static TASK: Mutex<TaskStateSegment> = Mutex::new(TaskStateSegment::new());
 
+/// Double fault, page fault and privilege stacks of the BSP until allocate_stacks, so early faults still reach their handlers
+/// Separate so a double fault while on the page fault stack does not overwrite its frame
+static mut EARLY_STACKS: [[u8; EARLY_STACK_SIZE]; 3] = [[0; EARLY_STACK_SIZE]; 3];
+
+const EARLY_STACK_SIZE: usize = 4 * 4096;
+
 pub const KCS: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
 pub const KDS: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
 pub const UDS: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
 pub const UCS: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
 pub const TSS: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
 
-pub fn init() {
-    // LOCK SAFETY: ONLY LOCKED HERE
-    let mut tss = TASK.lock();
+/// Selectors loaded by init, the same on every CPU since every GDT has the same layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
 
-    tss.interrupt_stack_table[0] = {
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Fault and privilege stacks of one CPU
+#[repr(C)]
+struct CpuStacks {
+    double_fault: GuardedStack,
+    page_fault: GuardedStack,
+    privilege: GuardedStack,
+}
 
-        VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
-    };
-    tss.interrupt_stack_table[1] = {
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+impl CpuStacks {
+    /// Allocated with unmapped guard pages, needs the memory manager
+    fn allocate() -> &'static CpuStacks {
+        // SAFETY: ZEROED STACKS ARE VALID
+        let stacks = unsafe { VirtPages::<CpuStacks>::try_new_zeroed() }.expect("No memory for CPU stacks!!!").leak();
+        Self::protect(stacks);
+        stacks
+    }
 
-        VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
-    };
-    tss.privilege_stack_table[0] = {
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    fn stacks(this: *const Self) -> [*const GuardedStack; 3] {
+        // SAFETY: ONLY COMPUTES FIELD ADDRESSES
+        unsafe { [&raw const (*this).double_fault, &raw const (*this).page_fault, &raw const (*this).privilege] }
+    }
+
+    fn install(this: *const Self, tss: &mut TaskStateSegment) {
+        let [double_fault, page_fault, privilege] = Self::stacks(this);
+        tss.interrupt_stack_table[0] = GuardedStack::top(double_fault);
+        tss.interrupt_stack_table[1] = GuardedStack::top(page_fault);
+        tss.privilege_stack_table[0] = GuardedStack::top(privilege);
+    }
 
-        VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+    /// Needs the memory manager
+    fn protect(this: *const Self) {
+        Self::stacks(this).into_iter().for_each(GuardedStack::protect);
+    }
+}
+
+/// Builds and loads the GDT and TSS of the calling CPU
+/// CPU 0 (the BSP) uses static tables and runs before memory init, every other CPU allocates its own
+pub fn init(cpu: usize) -> Selectors {
+    let (gdt, tss) = if cpu == 0 {
+        // LOCK SAFETY: ONLY LOCKED HERE AND IN allocate_stacks
+        let mut tss = TASK.lock();
+        let early_top = |index: usize| VirtAddr::from_ptr(&raw const EARLY_STACKS) + ((index + 1) * EARLY_STACK_SIZE) as u64;
+        tss.interrupt_stack_table[0] = early_top(0);
+        tss.interrupt_stack_table[1] = early_top(1);
+        tss.privilege_stack_table[0] = early_top(2);
+        // LOCK SAFETY: ONLY LOCKED HERE
+        (MutexGuard::leak(GLOBAL.lock()), &*tss as *const TaskStateSegment)
+    } else {
+        let stacks = CpuStacks::allocate();
+
+        let (gdt, tss) = VirtFrame::new((GlobalDescriptorTable::new(), TaskStateSegment::new())).leak();
+        CpuStacks::install(stacks, tss);
+        (gdt, &*tss as *const TaskStateSegment)
     };
-    // LOCK SAFETY: ONLY LOCKED HERE
-    let mut gdt = GLOBAL.lock();
 
     assert_eq!(gdt.append(Descriptor::kernel_code_segment()), KCS);
     assert_eq!(gdt.append(Descriptor::kernel_data_segment()), KDS);
     assert_eq!(gdt.append(Descriptor::user_data_segment()), UDS);
     assert_eq!(gdt.append(Descriptor::user_code_segment()), UCS);
-    assert_eq!(gdt.append(Descriptor::tss_segment(MutexGuard::leak(tss))), TSS);
+    // SAFETY: THE TSS IS STATIC OR LEAKED
+    assert_eq!(gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss) }), TSS);
 
-    MutexGuard::leak(gdt).load();
+    gdt.load();
 
     // SAFETY: SEGMENTS ARE VALID AND LOADED
     unsafe {
@@ -54,4 +104,18 @@ pub fn init() {
 
     // SAFETY: TSS IS VALID
     unsafe { load_tss(TSS) };
+
+    Selectors { kernel_code: KCS, kernel_data: KDS, user_data: UDS, user_code: UCS, tss: TSS }
+}
+
+/// Moves the BSP off the early stack onto allocated guarded stacks, needs the memory manager
+pub fn allocate_stacks() {
+    let stacks = CpuStacks::allocate();
+
+    without_interrupts(|| {
+        // LOCK SAFETY: ONLY LOCKED HERE AND IN init
+        CpuStacks::install(stacks, &mut TASK.lock());
+    });
+
+    debug!("Fault stacks allocated with guard pages");
 }
@@ -0,0 +1,113 @@
+/// Loadable segment
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+#[allow(dead_code)]
+pub const PF_R: u32 = 1 << 2;
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// Minimal little endian ELF64 reader
+#[derive(Clone, Copy, Debug)]
+pub struct Elf<'a> {
+    raw: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl<'a> Elf<'a> {
+    /// Returns None if raw is not a little endian ELF64 file
+    pub fn new(raw: &'a [u8]) -> Option<Self> {
+        (raw.get(..4)? == MAGIC && *raw.get(4)? == CLASS_64 && *raw.get(5)? == DATA_LITTLE_ENDIAN).then_some(Self { raw })
+    }
+
+    /// Skips headers that are out of bounds
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let raw = self.raw;
+        let offset = read_u64(raw, 0x20).unwrap_or(0) as usize;
+        let entry_size = read_u16(raw, 0x36).unwrap_or(0) as usize;
+        let count = if entry_size >= PROGRAM_HEADER_SIZE { read_u16(raw, 0x38).unwrap_or(0) as usize } else { 0 };
+
+        (0..count).filter_map(move |index| {
+            let header = offset.checked_add(index.checked_mul(entry_size)?)?;
+
+            Some(ProgramHeader {
+                kind: read_u32(raw, header)?,
+                flags: read_u32(raw, header + 0x04)?,
+                offset: read_u64(raw, header + 0x08)?,
+                vaddr: read_u64(raw, header + 0x10)?,
+                file_size: read_u64(raw, header + 0x20)?,
+                mem_size: read_u64(raw, header + 0x28)?,
+            })
+        })
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// (file offset, size, link) of every section header that is in bounds
+    fn sections(&self) -> impl Iterator<Item = (u32, usize, usize, u32)> + 'a {
+        let raw = self.raw;
+        let offset = read_u64(raw, 0x28).unwrap_or(0) as usize;
+        let entry_size = read_u16(raw, 0x3A).unwrap_or(0) as usize;
+        let count = if entry_size >= SECTION_HEADER_SIZE { read_u16(raw, 0x3C).unwrap_or(0) as usize } else { 0 };
+
+        (0..count).filter_map(move |index| {
+            let header = offset.checked_add(index.checked_mul(entry_size)?)?;
+
+            Some((read_u32(raw, header + 0x04)?, read_u64(raw, header + 0x18)? as usize, read_u64(raw, header + 0x20)? as usize, read_u32(raw, header + 0x28)?))
+        })
+    }
+
+    /// Function symbol containing the link time address addr and the offset into it, None if stripped or unknown
+    pub fn symbol_at(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let raw = self.raw;
+        let (_, symbols, symbols_size, link) = self.sections().find(|&(kind, ..)| kind == SHT_SYMTAB)?;
+        let (_, strings, strings_size, _) = self.sections().nth(link as usize)?;
+        let strings = raw.get(strings..strings.checked_add(strings_size)?)?;
+
+        (0..symbols_size / SYMBOL_SIZE).find_map(|index| {
+            let symbol = symbols + index * SYMBOL_SIZE;
+            let value = read_u64(raw, symbol + 0x08)?;
+            let size = read_u64(raw, symbol + 0x10)?;
+
+            if *raw.get(symbol + 0x04)? & 0xF != STT_FUNC || !(value..value.checked_add(size.max(1))?).contains(&addr) {
+                return None;
+            }
+
+            let name = strings.get(read_u32(raw, symbol)? as usize..)?;
+            let name = &name[..name.iter().position(|&byte| byte == 0)?];
+
+            Some((str::from_utf8(name).ok()?, addr - value))
+        })
+    }
+}
+
+fn read_u16(raw: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(raw.get(offset..offset.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn read_u32(raw: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(raw.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+fn read_u64(raw: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(raw.get(offset..offset.checked_add(8)?)?.try_into().ok()?))
+}
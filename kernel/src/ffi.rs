@@ -1,7 +1,7 @@
-use core::{marker::PhantomData, slice};
+use core::{fmt::{self, Debug, Display}, marker::PhantomData, slice, str::Utf8Error};
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct FFIStr<'a> {
     ptr: *const u8,
     len: usize,
@@ -14,9 +14,79 @@ impl<'a> From<&'a str> for FFIStr<'a> {
     }
 }
 
+impl<'a> FFIStr<'a> {
+    /// Like the Into conversion but reports invalid UTF-8 instead of substituting a placeholder
+    pub fn try_as_str(&self) -> Result<&'a str, Utf8Error> {
+        // Default (null) strings are empty
+        if self.ptr.is_null() || self.len == 0 {
+            return Ok("");
+        }
+
+        // SAFETY: SHOULD BE SAFE
+        str::from_utf8(unsafe { slice::from_raw_parts(self.ptr, self.len) })
+    }
+}
+
 impl<'a> Into<&'a str> for FFIStr<'a> {
     fn into(self) -> &'a str {
-        // SAFETY: SHOULD BE SAFE
-        str::from_utf8(unsafe { slice::from_raw_parts(self.ptr, self.len) }).unwrap_or("malformed_ffi_str")
+        self.try_as_str().unwrap_or("malformed_ffi_str")
+    }
+}
+
+impl Debug for FFIStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(<FFIStr as Into<&str>>::into(*self), f)
+    }
+}
+
+impl Display for FFIStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(<FFIStr as Into<&str>>::into(*self), f)
+    }
+}
+
+/// Slice passed across the module ABI
+#[repr(C)]
+pub struct FFISlice<'a, T> {
+    ptr: *const T,
+    len: usize,
+    phantom: PhantomData<&'a [T]>,
+}
+
+impl<'a, T> FFISlice<'a, T> {
+    pub fn as_slice(&self) -> &'a [T] {
+        // Default (null) slices are empty
+        if self.ptr.is_null() || self.len == 0 {
+            return &[];
+        }
+
+        // SAFETY: CREATED FROM A SLICE WITH LIFETIME 'a
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<'a, T> From<&'a [T]> for FFISlice<'a, T> {
+    fn from(value: &'a [T]) -> Self {
+        FFISlice { ptr: value.as_ptr(), len: value.len(), phantom: PhantomData }
+    }
+}
+
+impl<T> Clone for FFISlice<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for FFISlice<'_, T> {}
+
+impl<T> Default for FFISlice<'_, T> {
+    fn default() -> Self {
+        FFISlice { ptr: core::ptr::null(), len: 0, phantom: PhantomData }
+    }
+}
+
+impl<T: Debug> Debug for FFISlice<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_slice(), f)
     }
 }
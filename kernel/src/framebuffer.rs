@@ -4,18 +4,71 @@ use bootloader_api::info::{FrameBuffer, FrameBufferInfo};
 use spin::Mutex;
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::{text::{font::Font, format::Color}, time::Time};
+use crate::{config, log::Timestamp, text::{font::{glyph_for, FontKind}, format::{AnsiEvent, AnsiParser, Color}}};
 
-type FramePrinterFont = crate::config::framebuffer::Font;
+const TAB_WIDTH: usize = 8;
+/// Columns kept per history line, longer lines are cut off in the history only
+const HISTORY_COLUMNS: usize = 256;
+const HISTORY_LINES: usize = config::framebuffer::HISTORY_LINES;
+
+#[derive(Clone, Copy)]
+struct Cell {
+    /// Null for cells never written (drawn with the default background)
+    c: Char,
+    fg: Color,
+    bg: Color,
+}
+
+const EMPTY_CELL: Cell = Cell { c: Char::Null, fg: Color(0, 0, 0), bg: Color(0, 0, 0) };
+
+/// Ring of the last HISTORY_LINES lines, the newest one is the line currently written
+struct History {
+    lines: [[Cell; HISTORY_COLUMNS]; HISTORY_LINES],
+    newest: usize,
+    len: usize,
+}
+
+impl History {
+    fn new_line(&mut self) {
+        self.newest = (self.newest + 1) % HISTORY_LINES;
+        self.lines[self.newest] = [EMPTY_CELL; HISTORY_COLUMNS];
+        self.len = (self.len + 1).min(HISTORY_LINES);
+    }
+
+    fn set(&mut self, column: usize, cell: Cell) {
+        if column < HISTORY_COLUMNS {
+            self.lines[self.newest][column] = cell;
+        }
+    }
+
+    /// Line back lines above the newest one
+    fn line(&self, back: usize) -> Option<&[Cell; HISTORY_COLUMNS]> {
+        (back < self.len).then(|| &self.lines[(self.newest + HISTORY_LINES - back) % HISTORY_LINES])
+    }
+}
+
+/// Too large for the early stack, so it lives here and is only referenced by the frame printer
+static mut HISTORY: History = History { lines: [[EMPTY_CELL; HISTORY_COLUMNS]; HISTORY_LINES], newest: 0, len: 1 };
 
 pub struct FramePrinter {
     framebuffer: &'static mut FrameBuffer,
     info: FrameBufferInfo,
     line_count: usize,
     line_pos: usize,
+    /// Lines scrolled but not yet moved on screen
+    pending_scroll: usize,
     fg_color: Color,
     bg_color: Color,
-    newline: bool,
+    default_fg_color: Color,
+    default_bg_color: Color,
+    /// Last ANSI foreground index, so bold can brighten it
+    fg_index: Option<u8>,
+    bold: bool,
+    ansi: AnsiParser,
+    history: &'static mut History,
+    font: FontKind,
+    /// Lines the view is scrolled back into the history (0 = following the output)
+    scroll_back: usize,
 }
 
 static FRAMEBUFFER: Mutex<Option<FramePrinter>> = Mutex::new(None);
@@ -25,14 +78,25 @@ impl FramePrinter {
         // DEADLOCK SAFETY: ONLY USED ONCE BEFORE ANY PRINTS
         let mut framebuffer_guard = FRAMEBUFFER.lock();
 
+        let history = &raw mut HISTORY;
+
         *framebuffer_guard = Some(FramePrinter {
             info: framebuffer.info(),
             framebuffer,
             line_count: 0,
             line_pos: 0,
-            newline: true,
+            pending_scroll: 0,
             fg_color: Color(255, 255, 255),
             bg_color: Color(0, 0, 0),
+            default_fg_color: Color(255, 255, 255),
+            default_bg_color: Color(0, 0, 0),
+            fg_index: None,
+            bold: false,
+            ansi: AnsiParser::Ground,
+            // SAFETY: ONLY USED ONCE, SO THIS IS THE ONLY REFERENCE
+            history: unsafe { &mut *history },
+            font: config::framebuffer::FONT,
+            scroll_back: 0,
         });
 
         framebuffer_guard.as_mut().unwrap().framebuffer.buffer_mut().fill(0);
@@ -45,7 +109,15 @@ impl FramePrinter {
             // AVOID DEADLOCK
             match FRAMEBUFFER.try_lock() {
                 Some(mut guard) => match *guard {
-                    Some(ref mut fb) => fb.write_fmt(args),
+                    Some(ref mut fb) => {
+                        // New output always shows up
+                        if fb.scroll_back != 0 {
+                            fb.scroll_view(isize::MIN);
+                        }
+                        let result = fb.write_fmt(args);
+                        fb.flush_scroll();
+                        result
+                    },
                     // A missing frame printer is ok
                     None => Ok(()),
                 },
@@ -60,8 +132,11 @@ impl FramePrinter {
             match FRAMEBUFFER.try_lock() {
                 Some(mut guard) => match *guard {
                     Some(ref mut fb) => {
+                        fb.default_fg_color = fg_color;
+                        fb.default_bg_color = bg_color;
                         fb.fg_color = fg_color;
                         fb.bg_color = bg_color;
+                        fb.fg_index = None;
                     },
                     None => (),
                 },
@@ -75,13 +150,140 @@ impl FramePrinter {
         unsafe { FRAMEBUFFER.force_unlock() };
         Self::print_default_static(args)
     }
+
+    /// Scrolls the view half a screen back (or forward), ignored if the frame printer is missing or locked
+    pub fn scroll_page_default_static(back: bool) {
+        Self::with_default_static(|fb| {
+            let lines = (fb.info.height / fb.font.height() / 2).max(1) as isize;
+            fb.scroll_view(if back { lines } else { -lines });
+        });
+    }
 }
 
 impl FramePrinter {
-    fn set_color_at(&mut self, x: usize, y: usize, col: Color) -> core::fmt::Result {
-        let base_pos = ((self.info.height - FramePrinterFont::height() + y) * self.info.stride + (self.line_pos * FramePrinterFont::width() + x)) * self.info.bytes_per_pixel;
+    /// Moves the screen up by all pending lines at once, only copying rows that contain text
+    fn flush_scroll(&mut self) {
+        if self.pending_scroll == 0 {
+            return;
+        }
+
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let visible = self.info.height * row_bytes;
+        let shift = (self.pending_scroll * self.font.height()).min(self.info.height) * row_bytes;
+        // Everything above the written lines is still blank
+        let written_lines = self.line_count - self.pending_scroll + 1;
+        let written = visible - (written_lines * self.font.height()).min(self.info.height) * row_bytes;
         let buffer = self.framebuffer.buffer_mut();
-        match self.info.pixel_format {
+
+        let source = written.max(shift);
+        if source < visible {
+            buffer.copy_within(source..visible, source - shift);
+        }
+        buffer[visible - shift..].fill(0);
+
+        self.pending_scroll = 0;
+    }
+
+    /// Applies ANSI select graphic rendition parameters, unknown ones are ignored
+    fn apply_sgr(&mut self, params: &[u16]) {
+        // ESC[m is a reset
+        let params = if params.is_empty() { &[0][..] } else { params };
+
+        for &param in params {
+            match param {
+                0 => {
+                    self.fg_color = self.default_fg_color;
+                    self.bg_color = self.default_bg_color;
+                    self.fg_index = None;
+                    self.bold = false;
+                },
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.fg_index = Some((param - 30) as u8),
+                39 => {
+                    self.fg_color = self.default_fg_color;
+                    self.fg_index = None;
+                },
+                40..=47 => self.bg_color = Color::ansi((param - 40) as u8, false),
+                49 => self.bg_color = self.default_bg_color,
+                90..=97 => {
+                    self.fg_color = Color::ansi((param - 90) as u8, true);
+                    self.fg_index = None;
+                },
+                100..=107 => self.bg_color = Color::ansi((param - 100) as u8, true),
+                _ => (),
+            }
+
+            if let Some(index) = self.fg_index {
+                self.fg_color = Color::ansi(index, self.bold);
+            }
+        }
+    }
+
+    /// Fills the cell at line_pos with the background color
+    fn clear_cell(&mut self) -> core::fmt::Result {
+        self.history.set(self.line_pos, Cell { c: Char::Space, fg: self.fg_color, bg: self.bg_color });
+        self.draw_char(0, self.line_pos, Char::Space, self.bg_color, self.bg_color)
+    }
+
+    /// Draws c at column of the text row (counted from the bottom of the screen)
+    fn draw_char(&mut self, row: usize, column: usize, c: Char, fg: Color, bg: Color) -> core::fmt::Result {
+        let glyph = self.font.get_char(c);
+        let top = self.info.height - (row + 1) * self.font.height();
+
+        for y in 0..self.font.height() {
+            for x in 0..self.font.width() {
+                let select = glyph[y * self.font.width() + (self.font.width() - x - 1)];
+                let base_pos = ((top + y) * self.info.stride + (column * self.font.width() + x)) * self.info.bytes_per_pixel;
+                self.write_pixel(base_pos, if select { fg } else { bg }).map_err(|_| core::fmt::Error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the view by lines (positive = back) within the history and redraws the screen from it
+    fn scroll_view(&mut self, lines: isize) {
+        let rows = self.info.height / self.font.height();
+        let max = self.history.len.saturating_sub(rows);
+        let scroll_back = self.scroll_back.saturating_add_signed(lines).min(max);
+
+        if scroll_back == self.scroll_back && self.pending_scroll == 0 {
+            return;
+        }
+
+        self.scroll_back = scroll_back;
+        self.redraw();
+    }
+
+    /// Redraws every text row from the history at the current scroll back
+    fn redraw(&mut self) {
+        // Every row gets redrawn anyway
+        self.pending_scroll = 0;
+
+        let rows = self.info.height / self.font.height();
+        let columns = self.info.width / self.font.width();
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let cell = self.history.line(row + self.scroll_back).and_then(|line| line.get(column)).copied().unwrap_or(EMPTY_CELL);
+                let result = match cell.c {
+                    Char::Null => self.draw_char(row, column, Char::Space, self.default_bg_color, self.default_bg_color),
+                    c => self.draw_char(row, column, c, cell.fg, cell.bg),
+                };
+
+                // Unsupported pixel format, nothing to redraw
+                if result.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, base_pos: usize, col: Color) -> Result<(), ()> {
+        let pixel_format = self.info.pixel_format;
+        let buffer = self.framebuffer.buffer_mut();
+        match pixel_format {
             bootloader_api::info::PixelFormat::Rgb => {
                 buffer[base_pos + 0] = col.0;
                 buffer[base_pos + 1] = col.1;
@@ -104,45 +306,127 @@ impl FramePrinter {
                 buffer[base_pos + blue_position as usize]   = col.2;
                 Ok(())
             },
-            _ => Err(core::fmt::Error),
+            _ => Err(()),
         }
     }
 }
 
-impl Write for FramePrinter {
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        if self.newline {
-            self.newline = false;
-            let time = Time::boot_time_ns();
-            self.write_fmt(format_args!("[{:03}.{:03}] ", (time / 1000000000) % 1000, (time / 1000000) % 1000))?;
+#[allow(dead_code)]
+impl FramePrinter {
+    /// Runs f on the default frame printer, returns None if it is missing or locked
+    pub fn with_default_static<R>(f: impl FnOnce(&mut FramePrinter) -> R) -> Option<R> {
+        without_interrupts(|| {
+            // AVOID DEADLOCK
+            FRAMEBUFFER.try_lock()?.as_mut().map(f)
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Sets one pixel in absolute framebuffer coordinates, errors if out of bounds or the pixel format is unsupported
+    pub fn draw_pixel(&mut self, x: usize, y: usize, col: Color) -> Result<(), ()> {
+        if x >= self.info.width || y >= self.info.height {
+            return Err(());
+        }
+
+        self.write_pixel((y * self.info.stride + x) * self.info.bytes_per_pixel, col)
+    }
+
+    /// Fills a rectangle clipped to the screen, errors if the pixel format is unsupported
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, col: Color) -> Result<(), ()> {
+        let x_end = x.saturating_add(w).min(self.info.width);
+        let y_end = y.saturating_add(h).min(self.info.height);
+
+        for y in y..y_end {
+            for x in x..x_end {
+                self.write_pixel((y * self.info.stride + x) * self.info.bytes_per_pixel, col)?;
+            }
         }
 
-        let c = c.as_ascii().unwrap_or(Char::EndOfTransmission /* SQUARE */);
+        Ok(())
+    }
+
+    pub fn fill(&mut self, col: Color) -> Result<(), ()> {
+        self.draw_rect(0, 0, self.info.width, self.info.height, col)
+    }
+
+    pub fn font(&self) -> FontKind {
+        self.font
+    }
+
+    /// Switches the font, clears the screen and redraws the latest history on the new grid
+    pub fn set_font(&mut self, font: FontKind) {
+        self.font = font;
+        self.scroll_back = 0;
+        self.line_pos = self.line_pos.min(self.info.width / font.width());
+
+        // Unsupported pixel formats can not be drawn on anyway
+        if self.fill(self.default_bg_color).is_ok() {
+            self.redraw();
+        }
+    }
+}
+
+impl Write for FramePrinter {
+    fn write_char(&mut self, c: char) -> core::fmt::Result {
+        let c = match self.ansi.feed(c) {
+            Some(AnsiEvent::Char(c)) => c,
+            Some(AnsiEvent::Sgr(params, count)) => {
+                self.apply_sgr(&params[..count]);
+                return Ok(());
+            },
+            None => return Ok(()),
+        };
+        let c = glyph_for(c);
         match c {
             Char::LineFeed => {
-                self.framebuffer.buffer_mut().copy_within(self.info.stride * self.info.bytes_per_pixel * FramePrinterFont::height().., 0);
-                self.framebuffer.buffer_mut().split_at_mut((self.info.height - FramePrinterFont::height()) * self.info.stride * self.info.bytes_per_pixel).1.fill(0);
+                // Deferred so runs of newlines only move the screen once
+                self.pending_scroll += 1;
                 self.line_pos = 0;
-                self.newline = true;
                 self.line_count += 1;
+                self.history.new_line();
                 Ok(())
             },
             Char::CarriageReturn => {
-                self.line_pos = 10;
+                // Indent wrapped lines past the timestamp
+                self.line_pos = Timestamp::width(config::LOG_TIMESTAMP);
                 Ok(())
             },
-            //TODO: ANSI OR SMTH FOR COLORS
-            _ => {
-                let c = FramePrinterFont::get_char(c);
-                if self.line_pos == self.info.width / FramePrinterFont::width() {
+            Char::CharacterTabulation => {
+                let columns = self.info.width / self.font.width();
+                if self.line_pos == columns {
                     write!(self, "\n\r")?;
                 }
-                for y in 0..FramePrinterFont::height() {
-                    for x in 0..FramePrinterFont::width() {
-                        let select = c[y * FramePrinterFont::width() + (FramePrinterFont::width() - x - 1)];
-                        self.set_color_at(x, y, if select { self.fg_color } else { self.bg_color })?;
-                    }
+                self.flush_scroll();
+                let next = (self.line_pos / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.line_pos < next.min(columns) {
+                    self.clear_cell()?;
+                    self.line_pos += 1;
+                }
+                Ok(())
+            },
+            Char::Backspace => {
+                // No-op at the line start
+                if self.line_pos > 0 {
+                    self.flush_scroll();
+                    self.line_pos -= 1;
+                    self.clear_cell()?;
+                }
+                Ok(())
+            },
+            _ => {
+                if self.line_pos == self.info.width / self.font.width() {
+                    write!(self, "\n\r")?;
                 }
+                self.flush_scroll();
+                self.history.set(self.line_pos, Cell { c, fg: self.fg_color, bg: self.bg_color });
+                self.draw_char(0, self.line_pos, c, self.fg_color, self.bg_color)?;
                 self.line_pos += 1;
                 Ok(())
             }
@@ -2,7 +2,7 @@ use core::str::Utf8Error;
 
 use spin::RwLock;
 
-use crate::debug;
+use crate::{debug, error, warn};
 
 pub struct InitRamFs {
     raw: Option<&'static [u8]>,
@@ -10,20 +10,71 @@ pub struct InitRamFs {
 
 static INITRAMFS: RwLock<InitRamFs> = RwLock::new(InitRamFs { raw: None });
 
+/// Must match the packer in the top level build.rs
+const MAGIC: [u8; 4] = *b"EVRD";
+const VERSION: u32 = 2;
+/// Magic, version, crc32 of everything after the header, padding and file count (u64)
+const HEADER_LEN: usize = 4 + 4 + 4 + 4 + size_of::<u64>();
+const FILE_COUNT_OFFSET: usize = 16;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// IEEE CRC32 (same as zlib)
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
 pub(crate) fn init(ramdisk_location: u64, ramdisk_len: u64) {
     // SAFETY: GUARANTEED BY BOOTLOADER
     let file_slice = unsafe { core::slice::from_raw_parts(ramdisk_location as *const u8, ramdisk_len as usize) };
 
+    if file_slice.len() < HEADER_LEN || file_slice[0..4] != MAGIC {
+        error!("InitRamFs has no valid header, ignoring it");
+        return;
+    }
+
+    let version = u32::from_le_bytes(file_slice[4..8].try_into().unwrap());
+    if version != VERSION {
+        error!("InitRamFs version {} does not match the kernel ({}), ignoring it", version, VERSION);
+        return;
+    }
+
+    let expected = u32::from_le_bytes(file_slice[8..12].try_into().unwrap());
+    let actual = crc32(&file_slice[HEADER_LEN..]);
+    // Still used so the problem can be diagnosed
+    if expected != actual {
+        error!("InitRamFs checksum mismatch (expected 0x{:08x}, got 0x{:08x}), the disk image is corrupt", expected, actual);
+    }
+
     INITRAMFS.write().raw = Some(file_slice);
 
     debug!("InitRamFs contents:");
 
-    for (file_name, file_content) in InitRamFs::iter() {
-        debug!("    File `{}` with size 0x{:016x} bytes", file_name, file_content.len());
+    let files = InitRamFs::iter();
+    for index in 0..files.file_count {
+        match entry(file_slice, index) {
+            Ok((file_name, file_content)) => debug!("    File `{}` with size 0x{:016x} bytes", file_name, file_content.len()),
+            Err(err) => warn!("InitRamFs entry {} is corrupt ({}), skipping it", index, err),
+        }
     }
 }
 
 impl InitRamFs {
+    /// Matches the full path (like `bin/init`)
     pub fn open_file(name: &str) -> Option<&'static [u8]> {
         Self::iter().find_map(|(file, content)| (file == name).then(|| content))
     }
@@ -32,12 +83,16 @@ impl InitRamFs {
         Self::iter().find_map(|(file, content)| (file == name).then(|| str::from_utf8(content)))
     }
 
+    /// Empty if the ramdisk was rejected, corrupt entries are skipped
     pub fn iter() -> InitRamFileIterator {
-        let mut file_count = [0; 8];
-        file_count.copy_from_slice(&INITRAMFS.read().raw.unwrap()[0..size_of::<usize>()]);
-        let file_count = usize::from_le_bytes(file_count);
+        let Some(raw) = INITRAMFS.read().raw else {
+            return InitRamFileIterator { raw: &[], file_count: 0, current_file: 0 };
+        };
 
-        InitRamFileIterator { raw: INITRAMFS.read().raw.unwrap(), file_count, current_file: 0 }
+        // Never more entries than the table can hold
+        let file_count = read_u64(raw, FILE_COUNT_OFFSET).map_or(0, |count| usize::try_from(count).unwrap_or(usize::MAX)).min((raw.len() - HEADER_LEN) / ENTRY_LEN);
+
+        InitRamFileIterator { raw, file_count, current_file: 0 }
     }
 }
 
@@ -51,30 +106,50 @@ impl Iterator for InitRamFileIterator {
     type Item = (&'static str, &'static [u8]);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_file >= self.file_count {
-            None
-        } else {
-            let table_slice = &self.raw[8..];
-            let current_slice = &table_slice[3 * 8 * self.current_file..3 * 8 * (self.current_file + 1)];
-
-            let mut buffer = [0; 8];
-            buffer.copy_from_slice(&current_slice[0..8]);
-            let name_offset = usize::from_le_bytes(buffer);
-            buffer.copy_from_slice(&current_slice[8..8 * 2]);
-            let name_len = usize::from_le_bytes(buffer);
-            let file_offset = name_offset + name_len;
-            buffer.copy_from_slice(&current_slice[8 * 2..8 * 3]);
-            let file_len = usize::from_le_bytes(buffer);
-
+        while self.current_file < self.file_count {
+            let file = entry(self.raw, self.current_file);
             self.current_file += 1;
 
-            Some((str::from_utf8(&self.raw[name_offset..name_offset + name_len]).expect("InitRamFs file name invalid!!!"), &self.raw[file_offset..file_offset + file_len]))
+            if let Ok(file) = file {
+                return Some(file);
+            }
         }
+
+        None
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.file_count - self.current_file, Some(self.file_count - self.current_file))
+        (0, Some(self.file_count - self.current_file))
     }
 }
 
-impl ExactSizeIterator for InitRamFileIterator {}
+/// Name offset, name length and file length, each a u64 like in the packer in build.rs
+const ENTRY_FIELD_LEN: usize = size_of::<u64>();
+const ENTRY_NAME_OFFSET: usize = 0;
+const ENTRY_NAME_LEN_OFFSET: usize = ENTRY_NAME_OFFSET + ENTRY_FIELD_LEN;
+const ENTRY_FILE_LEN_OFFSET: usize = ENTRY_NAME_LEN_OFFSET + ENTRY_FIELD_LEN;
+const ENTRY_LEN: usize = ENTRY_FILE_LEN_OFFSET + ENTRY_FIELD_LEN;
+
+/// Validates every offset against the slice, the error describes the problem
+fn entry(raw: &'static [u8], index: usize) -> Result<(&'static str, &'static [u8]), &'static str> {
+    let table = HEADER_LEN + index * ENTRY_LEN;
+
+    let name_offset = read_field(raw, table + ENTRY_NAME_OFFSET)?;
+    let name_len = read_field(raw, table + ENTRY_NAME_LEN_OFFSET)?;
+    let file_len = read_field(raw, table + ENTRY_FILE_LEN_OFFSET)?;
+
+    let file_offset = name_offset.checked_add(name_len).ok_or("name out of bounds")?;
+    let name = raw.get(name_offset..file_offset).ok_or("name out of bounds")?;
+    let file = raw.get(file_offset..file_offset.checked_add(file_len).ok_or("file out of bounds")?).ok_or("file out of bounds")?;
+
+    Ok((str::from_utf8(name).map_err(|_| "name is not utf8")?, file))
+}
+
+/// Table field as usize, a value that does not fit makes the entry corrupt
+fn read_field(raw: &[u8], offset: usize) -> Result<usize, &'static str> {
+    usize::try_from(read_u64(raw, offset).ok_or("table out of bounds")?).map_err(|_| "value too large")
+}
+
+fn read_u64(raw: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(raw.get(offset..offset.checked_add(size_of::<u64>())?)?.try_into().ok()?))
+}
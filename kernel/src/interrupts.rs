@@ -1,14 +1,25 @@
-use core::{mem::transmute, ops::RangeInclusive};
+use core::{fmt::{self, Display}, mem::transmute, ops::RangeInclusive, sync::atomic::{AtomicU16, Ordering}};
 
 use spin::{Mutex, MutexGuard};
-use x86_64::{instructions::{interrupts::enable, port::Port}, registers::control::Cr2, set_general_handler, structures::{idt::{EntryOptions, ExceptionVector, InterruptDescriptorTable, InterruptStackFrame}, paging::{PageSize, Size4KiB}}, PrivilegeLevel};
+use x86_64::{instructions::{interrupts::{enable, without_interrupts}, port::Port}, registers::control::Cr2, set_general_handler, structures::{idt::{EntryOptions, ExceptionVector, InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode}, paging::{PageSize, Size4KiB}}, PrivilegeLevel, VirtAddr};
 
-use crate::{error, modules::ps2::ps2_keyboard_interrupt, time::Time};
+use crate::{error, time::Time, warn};
+
+mod apic;
 
 static HANDLER: Mutex<InterruptDescriptorTable> = Mutex::new(InterruptDescriptorTable::new());
 
 // SAFETY: ONLY USED HERE
 static PIC: Mutex<Pic> = Mutex::new(unsafe { Pic::new() });
+/// DEADLOCK SAFETY: ONLY LOCKED WITHOUT INTERRUPTS OR IN THE INTERRUPT
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; 16]> = Mutex::new([None; 16]);
+
+/// Gets the EOI guard of its irq
+pub type IrqHandler = fn(PicEnd);
+
+/// One bit per irq that is unmasked on the PIC or IOAPIC
+/// Lpt1, Lpt2, Floppy, Free1-3 and Processor stay masked, the mouse until a driver detects it
+static ENABLED_IRQS: AtomicU16 = AtomicU16::new(!0b0011_1110_1110_0000);
 
 struct Pic {
     first_command: Port<u8>,
@@ -53,19 +64,7 @@ impl Pic {
             self.io_wait();
             self.mask();
         };
-        // SAFETY: VALID
-        unsafe {
-            let mut pit_cmd = Port::<u8>::new(0x43);
-            pit_cmd.write(0b0011_0110);// Channel 0b00, Access mode both 0b11, Mode 3 0b011, Binary Mode 0b0
-            let mut pit_data = Port::<u8>::new(0x40);
-            const PIT_RELOAD: u16 = 1193;// 1000 Hz (1000.1524 Hz) (999847.619 ns)
-            // const PIT_RELOAD: u16 = 120;// 10000 Hz (9943.18182 Hz) (100571.429 ns)
-            pit_data.write((PIT_RELOAD & 0xff) as u8);
-            pit_data.write((PIT_RELOAD >> 8) as u8);
-
-            Time::set_ps_tick_step(999847619);// 1000 Hz
-            // Time::set_ps_tick_step(100571429);// 10000 Hz
-        }
+        program_pit(PIT_DEFAULT_RELOAD);
     }
 
     fn io_wait(&mut self) {
@@ -73,11 +72,23 @@ impl Pic {
         unsafe { self.io_wait.write(0) };
     }
 
+    /// Masks every irq not in ENABLED_IRQS
     /// SAFETY: NO PROCESS CAN BE ACTIVE
     unsafe fn mask(&mut self) {
+        let enabled = ENABLED_IRQS.load(Ordering::Relaxed);
+
+        unsafe {
+            self.first_data.write(!enabled as u8);
+            self.second_data.write(!(enabled >> 8) as u8);
+        }
+    }
+
+    /// Masks every irq (when delivery moves to the APIC)
+    /// SAFETY: NO PROCESS CAN BE ACTIVE
+    unsafe fn disable(&mut self) {
         unsafe {
-            self.first_data.write(0b1110_0000);// Disable Lpt1, Lpt2 and Floppy
-            self.second_data.write(0b0011_1110);// Disable Processor, Mouse, Free3, Free2 and Free1
+            self.first_data.write(0xFF);
+            self.second_data.write(0xFF);
         }
     }
 
@@ -86,15 +97,12 @@ impl Pic {
         // SAFETY: VALID ONLY HERE
         let pic_guard = unsafe { PicEnd::new(irq) };
 
-        match irq {
-            PicInterrupt::Timer => Time::tick_step(pic_guard),//TODO: SCHEDULE? MAYBE CHECK FOR INTERRUPT IN INTERRUPT WITH LOCK?
-            PicInterrupt::Keyboard => ps2_keyboard_interrupt(),
-            PicInterrupt::Com2 => todo!("{:?}", irq),
-            PicInterrupt::Com1 => todo!("{:?}", irq),
-            PicInterrupt::Cmos => todo!("{:?}", irq),
-            PicInterrupt::PrimaryAta => todo!("{:?}", irq),
-            PicInterrupt::SecondaryAta => todo!("{:?}", irq),
-            _ => unreachable!("Unexpected irq {:?}", irq),
+        // Copied out so the handler runs without the table locked
+        let handler = IRQ_HANDLERS.lock()[irq as usize];
+
+        match handler {
+            Some(handler) => handler(pic_guard),
+            None => warn!("Unhandled irq {:?}", irq),
         }
     }
 
@@ -110,6 +118,46 @@ impl Pic {
     }
 }
 
+/// PIT input clock is 3579545 / 3 Hz
+const PIT_CLOCK_NUMERATOR: u64 = 3_579_545;
+const PIT_CLOCK_DENOMINATOR: u64 = 3;
+const PIT_DEFAULT_RELOAD: u16 = 1193;// 1000 Hz (1000.1524 Hz) (999847.619 ns)
+
+/// Reload 0 means 65536
+fn program_pit(reload: u16) {
+    let divisor = if reload == 0 { 0x10000 } else { reload as u64 };
+
+    // SAFETY: VALID
+    unsafe {
+        let mut pit_cmd = Port::<u8>::new(0x43);
+        pit_cmd.write(0b0011_0110);// Channel 0b00, Access mode both 0b11, Mode 3 0b011, Binary Mode 0b0
+        let mut pit_data = Port::<u8>::new(0x40);
+        pit_data.write((reload & 0xff) as u8);
+        pit_data.write((reload >> 8) as u8);
+    }
+
+    Time::set_ps_tick_step(divisor * 1_000_000_000_000 * PIT_CLOCK_DENOMINATOR / PIT_CLOCK_NUMERATOR);
+}
+
+/// Reprograms the timer to the closest achievable rate, errors if hz is outside of roughly 19 Hz to 596 kHz
+#[allow(dead_code)]
+pub fn set_timer_hz(hz: u32) -> Result<(), ()> {
+    if hz == 0 {
+        return Err(());
+    }
+
+    let divisor = (PIT_CLOCK_NUMERATOR + PIT_CLOCK_DENOMINATOR * hz as u64 / 2) / (PIT_CLOCK_DENOMINATOR * hz as u64);
+
+    // Mode 3 needs a divisor of at least 2
+    if !(2..=0x10000).contains(&divisor) {
+        return Err(());
+    }
+
+    without_interrupts(|| program_pit(divisor as u16));
+
+    Ok(())
+}
+
 pub struct PicEnd {
     irq: PicInterrupt,
 }
@@ -123,6 +171,12 @@ impl PicEnd {
 
 impl Drop for PicEnd {
     fn drop(&mut self) {
+        if apic::active() {
+            // SAFETY: VALID
+            unsafe { apic::eoi() };
+            return;
+        }
+
         // SAFETY: UNLOCKED AFTERWARDS ANYWAY
         unsafe { PIC.force_unlock() };
         // SAFETY: VALID
@@ -135,7 +189,7 @@ const PIC_SECOND_RANGE: RangeInclusive<PicInterrupt> = PicInterrupt::Cmos..=PicI
 #[allow(unused)]
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum PicInterrupt {
+pub enum PicInterrupt {
     Timer           = 0x00,
     Keyboard        = 0x01,
     Cascade         = 0x02,// Do not use.
@@ -148,7 +202,7 @@ enum PicInterrupt {
     Free1           = 0x09,// Not important?
     Free2           = 0x0A,// Not important?
     Free3           = 0x0B,// Not important?
-    Mouse           = 0x0C,// Not used
+    Mouse           = 0x0C,
     Processor       = 0x0D,// Not important?
     PrimaryAta      = 0x0E,
     SecondaryAta    = 0x0F,
@@ -203,12 +257,129 @@ pub fn init() {
     });
 
     MutexGuard::leak(idt).load();
+
+    register_irq_handler(PicInterrupt::Timer, Time::tick_step);//TODO: SCHEDULE? MAYBE CHECK FOR INTERRUPT IN INTERRUPT WITH LOCK?
     
     PIC.lock().init();
     enable();
 }
 
+/// Sets the handler for irq replacing the old one
+pub fn register_irq_handler(irq: PicInterrupt, handler: IrqHandler) {
+    without_interrupts(|| IRQ_HANDLERS.lock()[irq as usize] = Some(handler));
+}
+
+/// Unmasks irq on the PIC or IOAPIC, for devices that are only enabled once detected
+pub fn enable_irq(irq: PicInterrupt) {
+    without_interrupts(|| {
+        ENABLED_IRQS.fetch_or(1 << irq as u8, Ordering::Relaxed);
+
+        if apic::active() {
+            // SAFETY: THE APIC IS ACTIVE
+            unsafe { apic::unmask(irq) };
+        } else {
+            // SAFETY: ONLY CHANGES THE MASK OF irq
+            unsafe { PIC.lock().mask() };
+        }
+    });
+}
+
+fn irq_enabled(irq: PicInterrupt) -> bool {
+    ENABLED_IRQS.load(Ordering::Relaxed) & (1 << irq as u8) != 0
+}
+
+/// Moves irq delivery from the PIC to the APIC if there is one, returns true if the APIC is used
+/// Needs the memory manager
+pub fn init_apic(rsdp: Option<u64>) -> bool {
+    // SAFETY: ONLY CALLED ONCE AFTER MEMORY INIT AND RSDP IS FROM THE BOOTLOADER
+    without_interrupts(|| unsafe { apic::init(&mut PIC.lock(), rsdp) })
+}
+
+/// Readable page fault error code
+struct PageFaultReason(u64);
+
+impl Display for PageFaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = PageFaultErrorCode::from_bits_truncate(self.0);
+
+        write!(f, "{} {} {}",
+            if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" },
+            if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) { "instruction fetch" } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { "write" } else { "read" },
+            if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "on a present page (protection violation)" } else { "on a non present page" },
+        )?;
+
+        for (flag, name) in [
+            (PageFaultErrorCode::MALFORMED_TABLE, "reserved bit set"),
+            (PageFaultErrorCode::PROTECTION_KEY, "protection key"),
+            (PageFaultErrorCode::SHADOW_STACK, "shadow stack"),
+            (PageFaultErrorCode::SGX, "sgx"),
+        ] {
+            if code.contains(flag) {
+                write!(f, ", {}", name)?;
+            }
+        }
+
+        write!(f, " (0x{:x})", self.0)
+    }
+}
+
+/// Everything known about a user mode exception
+#[derive(Clone, Copy, Debug)]
+pub struct UserFault {
+    pub vector: ExceptionVector,
+    pub frame: InterruptStackFrameValue,
+    pub error_code: Option<u64>,
+    /// Cr2 for page faults
+    pub fault_addr: Option<VirtAddr>,
+}
+
+/// Has to terminate the faulting process (switch away), returning panics
+pub type UserFaultHook = fn(&UserFault);
+
+/// DEADLOCK SAFETY: ONLY LOCKED WITHOUT INTERRUPTS OR IN THE INTERRUPT
+static USER_FAULT_HOOK: Mutex<Option<UserFaultHook>> = Mutex::new(None);
+
+/// Sets the handler for fatal user exceptions (the scheduler)
+#[allow(dead_code)]
+pub fn set_user_fault_hook(hook: UserFaultHook) {
+    without_interrupts(|| *USER_FAULT_HOOK.lock() = Some(hook));
+}
+
+/// Reports a user mode exception, traps return to the process and everything else goes to the fault hook
+fn handle_user_fault(vector: ExceptionVector, frame: &InterruptStackFrame, error_code: Option<u64>) {
+    let fault = UserFault {
+        vector,
+        frame: **frame,
+        error_code,
+        fault_addr: if vector == ExceptionVector::Page { Cr2::read().ok() } else { None },
+    };
+
+    match (fault.fault_addr, fault.error_code) {
+        (Some(addr), Some(code)) => error!("User page fault at {:?}: {} with frame:\n{:#?}", addr, PageFaultReason(code), fault.frame),
+        _ => error!("User exception {:?} (error {:?}) with frame:\n{:#?}", fault.vector, fault.error_code, fault.frame),
+    }
+
+    if matches!(vector, ExceptionVector::Breakpoint | ExceptionVector::Debug) {
+        return;
+    }
+
+    // Copied out so the hook runs without the lock
+    let hook = *USER_FAULT_HOOK.lock();
+
+    if let Some(hook) = hook {
+        hook(&fault);
+    }
+
+    // Returning would fault again forever
+    panic!("Fatal user exception {:?} at {:?} without a process to terminate!!!", vector, fault.frame.instruction_pointer);
+}
+
 fn handler_func(frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
+    // Spurious interrupts must not be acknowledged
+    if index == apic::SPURIOUS_VECTOR {
+        return;
+    }
+
     if frame.code_segment.rpl() == PrivilegeLevel::Ring0 {
         match ExceptionVector::try_from(index) {
             Ok(vector) => {
@@ -217,7 +388,7 @@ fn handler_func(frame: InterruptStackFrame, index: u8, error_code: Option<u64>)
                         if let Ok(fault_addr) = Cr2::read() && frame.stack_pointer.as_u64().wrapping_sub(fault_addr.as_u64()) <= Size4KiB::SIZE {
                             panic!("Kernel STACKOVERFLOW at rip 0x{:016x} detected!", frame.instruction_pointer);
                         }
-                        panic!("kernel page fault e {} with frame:\n{:#?}\nand addr: {:?}", error_code.unwrap(), frame, Cr2::read())
+                        panic!("Kernel page fault at {:?}: {} with frame:\n{:#?}", Cr2::read(), PageFaultReason(error_code.unwrap()), frame)
                     },
                     _ => unreachable!("Unexpected interrupt with error {:?} {:?} with frame:\n{:#?}", error_code, vector, frame),//Should be unreachable right?
                 }
@@ -233,10 +404,7 @@ fn handler_func(frame: InterruptStackFrame, index: u8, error_code: Option<u64>)
     } else {
         match ExceptionVector::try_from(index) {
             Ok(vector) => {
-                match vector {
-                    // TODO: COLLECT FATAL
-                    _ => error!("unhandled user exception {:?} at {:?}", vector, frame.instruction_pointer),
-                }
+                handle_user_fault(vector, &frame, error_code);
             },
             Err(_) => {
                 match PicInterrupt::try_from(index) {
@@ -0,0 +1,275 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use raw_cpuid::CpuId;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+use crate::{debug, mem::{map_mmio, OFFSET}, util::Volatile, warn};
+
+use super::{irq_enabled, Pic, PicInterrupt};
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const LAPIC_ID: usize = 0x020;
+const LAPIC_EOI: usize = 0x0B0;
+const LAPIC_SPURIOUS: usize = 0x0F0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+const DEFAULT_IOAPIC: u64 = 0xFEC0_0000;
+
+/// Legacy irqs given an IOAPIC entry, masked until enabled like on the PIC
+const ROUTED: [PicInterrupt; 8] = [
+    PicInterrupt::Timer,
+    PicInterrupt::Keyboard,
+    PicInterrupt::Com2,
+    PicInterrupt::Com1,
+    PicInterrupt::Cmos,
+    PicInterrupt::Mouse,
+    PicInterrupt::PrimaryAta,
+    PicInterrupt::SecondaryAta,
+];
+
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+static IOAPIC: AtomicU64 = AtomicU64::new(0);
+/// IOAPIC redirection entry per ISA irq, NOT_ROUTED if there is none
+static ENTRIES: [AtomicU8; 16] = [const { AtomicU8::new(NOT_ROUTED) }; 16];
+const NOT_ROUTED: u8 = u8::MAX;
+
+/// ISA irq routing from the MADT
+#[derive(Clone, Copy, Debug)]
+struct Madt {
+    ioapic: u64,
+    gsi_base: u32,
+    /// (gsi, mps inti flags) per ISA irq
+    isa: [(u32, u16); 16],
+}
+
+impl Default for Madt {
+    fn default() -> Self {
+        Self {
+            ioapic: DEFAULT_IOAPIC,
+            gsi_base: 0,
+            isa: core::array::from_fn(|irq| (irq as u32, 0)),
+        }
+    }
+}
+
+pub fn active() -> bool {
+    APIC_ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn supported() -> bool {
+    CpuId::new().get_feature_info().is_some_and(|info| info.has_apic())
+}
+
+/// Masks the PIC and delivers the legacy irqs through the local and IO APIC, returns false if there is no APIC
+/// SAFETY: MUST RUN ONCE WITHOUT INTERRUPTS AFTER MEMORY INIT, RSDP MUST BE VALID
+pub(super) unsafe fn init(pic: &mut Pic, rsdp: Option<u64>) -> bool {
+    if !supported() {
+        return false;
+    }
+
+    // SAFETY: RSDP IS VALID
+    let madt = rsdp.and_then(|rsdp| unsafe { parse_madt(rsdp) }).unwrap_or_else(|| {
+        warn!("No MADT found, assuming default IOAPIC routing");
+        Madt::default()
+    });
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    // SAFETY: APIC IS SUPPORTED
+    let base = unsafe { apic_base.read() };
+    // SAFETY: ONLY SETS THE ENABLE BIT
+    unsafe { apic_base.write(base | APIC_BASE_ENABLE) };
+
+    let (Some(lapic), Some(ioapic)) = (map_mmio(PhysAddr::new(base & APIC_BASE_MASK), 0x400), map_mmio(PhysAddr::new(madt.ioapic), 0x20)) else {
+        warn!("Could not map the APIC registers");
+        return false;
+    };
+
+    LAPIC.store(lapic.as_u64(), Ordering::Relaxed);
+    IOAPIC.store(ioapic.as_u64(), Ordering::Relaxed);
+
+    // SAFETY: REGISTERS ARE MAPPED
+    unsafe {
+        lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+
+        let destination = lapic_read(LAPIC_ID) >> 24;
+        let entries = ((ioapic_read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+
+        for irq in ROUTED {
+            let (gsi, flags) = madt.isa[irq as usize];
+            let Some(entry) = gsi.checked_sub(madt.gsi_base).filter(|&entry| entry < entries) else {
+                warn!("IRQ {:?} (GSI {}) is not handled by the IOAPIC", irq, gsi);
+                continue;
+            };
+
+            let mut low = (Pic::OFFSET + irq as u8) as u32;
+            // ISA defaults to active high edge triggered
+            if flags & 0b11 == 0b11 {
+                low |= REDIRECTION_ACTIVE_LOW;
+            }
+            if (flags >> 2) & 0b11 == 0b11 {
+                low |= REDIRECTION_LEVEL;
+            }
+            if !irq_enabled(irq) {
+                low |= REDIRECTION_MASKED;
+            }
+
+            ENTRIES[irq as usize].store(entry as u8, Ordering::Relaxed);
+
+            ioapic_write(IOAPIC_REDIRECTION + entry * 2 + 1, destination << 24);
+            ioapic_write(IOAPIC_REDIRECTION + entry * 2, low);
+        }
+
+        pic.disable();
+    }
+
+    APIC_ACTIVE.store(true, Ordering::Relaxed);
+
+    debug!("APIC @ Phys 0x{:016x} and IOAPIC @ Phys 0x{:016x} enabled", base & APIC_BASE_MASK, madt.ioapic);
+
+    true
+}
+
+/// Clears the mask bit of the redirection entry of irq, warns if it is not routed
+/// SAFETY: THE APIC MUST BE ACTIVE, RUN WITHOUT INTERRUPTS
+pub(super) unsafe fn unmask(irq: PicInterrupt) {
+    let entry = ENTRIES[irq as usize].load(Ordering::Relaxed);
+
+    if entry == NOT_ROUTED {
+        warn!("IRQ {:?} is not routed through the IOAPIC", irq);
+        return;
+    }
+
+    let register = IOAPIC_REDIRECTION + entry as u32 * 2;
+
+    // SAFETY: REGISTERS ARE MAPPED
+    unsafe { ioapic_write(register, ioapic_read(register) & !REDIRECTION_MASKED) };
+}
+
+/// SAFETY: NEEDS TO BE IN AN INTERRUPT WITH THE APIC ACTIVE
+pub(super) unsafe fn eoi() {
+    // SAFETY: REGISTERS ARE MAPPED
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// SAFETY: LAPIC MUST BE MAPPED
+unsafe fn lapic_read(register: usize) -> u32 {
+    // SAFETY: LAPIC IS MAPPED
+    unsafe { Volatile::<u32>::at(LAPIC.load(Ordering::Relaxed) as usize + register) }.read()
+}
+
+/// SAFETY: LAPIC MUST BE MAPPED
+unsafe fn lapic_write(register: usize, value: u32) {
+    // SAFETY: LAPIC IS MAPPED
+    unsafe { Volatile::<u32>::at(LAPIC.load(Ordering::Relaxed) as usize + register) }.write(value);
+}
+
+/// SAFETY: IOAPIC MUST BE MAPPED
+unsafe fn ioapic_read(register: u32) -> u32 {
+    let base = IOAPIC.load(Ordering::Relaxed) as usize;
+    // SAFETY: IOAPIC IS MAPPED
+    unsafe {
+        Volatile::<u32>::at(base + IOAPIC_SELECT).write(register);
+        Volatile::<u32>::at(base + IOAPIC_WINDOW).read()
+    }
+}
+
+/// SAFETY: IOAPIC MUST BE MAPPED
+unsafe fn ioapic_write(register: u32, value: u32) {
+    let base = IOAPIC.load(Ordering::Relaxed) as usize;
+    // SAFETY: IOAPIC IS MAPPED
+    unsafe {
+        Volatile::<u32>::at(base + IOAPIC_SELECT).write(register);
+        Volatile::<u32>::at(base + IOAPIC_WINDOW).write(value);
+    }
+}
+
+/// SAFETY: PHYSICAL RANGE MUST BE MAPPED AT OFFSET
+unsafe fn phys_slice(addr: u64, len: usize) -> &'static [u8] {
+    // SAFETY: MAPPED AT OFFSET
+    unsafe { core::slice::from_raw_parts((addr + OFFSET) as *const u8, len) }
+}
+
+fn read_u32(raw: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(raw.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(raw: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(raw.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// SAFETY: TABLE MUST BE A VALID ACPI TABLE
+unsafe fn sdt(addr: u64) -> &'static [u8] {
+    // SAFETY: HEADER IS VALID
+    let len = read_u32(unsafe { phys_slice(addr, 8) }, 4).unwrap() as usize;
+    // SAFETY: TABLE IS VALID
+    unsafe { phys_slice(addr, len) }
+}
+
+/// SAFETY: RSDP MUST BE VALID
+unsafe fn parse_madt(rsdp: u64) -> Option<Madt> {
+    // SAFETY: RSDP IS VALID
+    let rsdp = unsafe { phys_slice(rsdp, 36) };
+
+    if &rsdp[..8] != b"RSD PTR " {
+        return None;
+    }
+
+    // XSDT (64 bit entries) if revision 2 or later
+    let (root, entry_size) = match rsdp[15] {
+        0 => (read_u32(rsdp, 16)? as u64, 4),
+        _ => (read_u64(rsdp, 24)?, 8),
+    };
+
+    // SAFETY: ROOT TABLE FROM THE RSDP
+    let root = unsafe { sdt(root) };
+
+    let madt = root[36..].chunks_exact(entry_size).find_map(|entry| {
+        let addr = if entry_size == 4 { read_u32(entry, 0)? as u64 } else { read_u64(entry, 0)? };
+        // SAFETY: TABLE FROM THE ROOT TABLE
+        let table = unsafe { sdt(addr) };
+        (&table[..4] == b"APIC").then_some(table)
+    })?;
+
+    let mut info = Madt::default();
+    let mut found_ioapic = false;
+    let mut offset = 44;
+
+    while let (Some(&kind), Some(&len)) = (madt.get(offset), madt.get(offset + 1)) {
+        if len < 2 {
+            break;
+        }
+
+        let entry = madt.get(offset..offset + len as usize)?;
+
+        match kind {
+            // First IOAPIC
+            1 if !found_ioapic => {
+                info.ioapic = read_u32(entry, 4)? as u64;
+                info.gsi_base = read_u32(entry, 8)?;
+                found_ioapic = true;
+            },
+            // Interrupt source override
+            2 if entry.len() >= 10 && entry[3] < 16 => info.isa[entry[3] as usize] = (read_u32(entry, 4)?, u16::from_le_bytes([entry[8], entry[9]])),
+            _ => (),
+        }
+
+        offset += len as usize;
+    }
+
+    Some(info)
+}
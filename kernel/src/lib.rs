@@ -5,7 +5,7 @@
 #![feature(generic_const_exprs)]
 #![feature(abi_x86_interrupt)]
 
-use bootloader_api::BootInfo;
+use bootloader_api::{config::ApiVersion, info::{MemoryRegionKind, MemoryRegions}, BootInfo};
 
 pub mod text;
 pub(crate) mod framebuffer;
@@ -22,24 +22,65 @@ pub(crate) mod syscalls;
 pub mod modules;
 pub mod initramfs;
 pub mod ffi;
+pub(crate) mod elf;
+pub(crate) mod util;
+pub mod version;
 
 pub use mem::CONFIG as BOOT_CONFIG;
 
 pub fn init(boot_info: &'static mut BootInfo) {
     log::init(&mut boot_info.framebuffer);
     info!("Logging initialized");
+    log_boot_config(&boot_info.memory_regions, boot_info.api_version, boot_info.physical_memory_offset.into_option(), boot_info.kernel_addr, boot_info.kernel_len);
     initramfs::init(boot_info.ramdisk_addr.into_option().expect("Ramdisk missing!!!"), boot_info.ramdisk_len);
-    info!("InitRamFs initialized with {} files", initramfs::InitRamFs::iter().len());
-    descriptors::init();
+    info!("InitRamFs initialized with {} files", initramfs::InitRamFs::iter().count());
+    descriptors::init(0);
     info!("GDT & TSS initialized");
     interrupts::init();
     info!("IDT initialized");
     // SAFETY: MEMORY REGIONS ARE VALID AND LATER UNUSED
     unsafe { mem::init(&mut boot_info.memory_regions) };
-    syscalls::init();
+    mem::protect_kernel(boot_info.kernel_addr, boot_info.kernel_len, boot_info.kernel_image_offset);
+    // SAFETY: GUARANTEED BY BOOTLOADER AND PHYSICAL MEMORY IS MAPPED AT OFFSET
+    panic::set_kernel_image(unsafe { core::slice::from_raw_parts((boot_info.kernel_addr + mem::OFFSET) as *const u8, boot_info.kernel_len as usize) }, boot_info.kernel_image_offset);
+    descriptors::allocate_stacks();
+    if interrupts::init_apic(boot_info.rsdp_addr.into_option()) {
+        info!("APIC initialized");
+    } else {
+        info!("No APIC, using the legacy PIC");
+    }
+    syscalls::init(0);
     info!("SYSCALLS initialized");
     let (successful, total) = modules::init();
     info!("Modules initialized ({}/{})", successful, total);
     info!("Initialization complete!");
     print_init_msg!();
 }
+
+/// Dumps the resolved boot configuration for diagnosing bootloader misconfiguration
+/// Takes the fields separately since the framebuffer is already borrowed by the logger
+fn log_boot_config(regions: &MemoryRegions, version: ApiVersion, physical_memory_offset: Option<u64>, kernel_addr: u64, kernel_len: u64) {
+    // The boot info has no firmware field, but unknown regions carry the firmware specific type
+    let firmware = regions.iter().find_map(|region| match region.kind {
+        MemoryRegionKind::UnknownUefi(_) => Some("UEFI"),
+        MemoryRegionKind::UnknownBios(_) => Some("BIOS"),
+        _ => None,
+    }).unwrap_or("unknown");
+
+    info!("Build {} ({}), booted by bootloader v{}.{}.{} ({} firmware)", version::build_id(), version::build_profile(), version.version_major(), version.version_minor(), version.version_patch(), firmware);
+    debug!("Physical memory @ Virt 0x{:016x} (bootloader reports {:?})", mem::OFFSET, physical_memory_offset.map(|offset| offset as *const ()));
+    debug!("Heap @ Virt 0x{:016x} (0x{:x} bytes in 0x{:x} byte blocks)", mem::HEAP_VIRT_BASE, mem::HEAP_VIRT_SIZE, mem::HEAP_BLOCK_SIZE);
+    debug!("Mapping window @ Virt 0x{:016x} (0x{:x} bytes)", mem::MAP_VIRT_BASE, mem::MAP_VIRT_SIZE);
+    debug!("Kernel stack 0x{:x} bytes, kernel @ Phys 0x{:016x} (0x{:x} bytes)", mem::STACK_SIZE, kernel_addr, kernel_len);
+}
+
+/// Halts until the next interrupt forever, the kernel falls into this once init is done
+/// Work deferred by interrupt handlers runs after every wake up
+pub fn idle() -> ! {
+    x86_64::instructions::interrupts::enable();
+
+    loop {
+        x86_64::instructions::hlt();
+        modules::ps2::flush_leds();
+    }
+}
@@ -1,10 +1,10 @@
-use core::fmt::{self, Arguments};
+use core::{fmt::{self, Arguments, Display, Write}, sync::atomic::{AtomicBool, AtomicU8, Ordering}};
 
 use bootloader_api::info::{FrameBuffer, Optional};
 use spin::Mutex;
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::{framebuffer::FramePrinter, debug, serial::SerialPrinter, text::format::Color};
+use crate::{config::{self, LogLevel, LogTimestamp}, framebuffer::FramePrinter, debug, serial::SerialPrinter, text::format::Color, time::{self, Time}};
 
 pub fn init(framebuffer: &'static mut Optional<FrameBuffer>) {
     SerialPrinter::init();
@@ -17,20 +17,231 @@ pub fn init(framebuffer: &'static mut Optional<FrameBuffer>) {
 
 pub struct Log {}
 
+/// Subsystems with their own runtime log level (used with target: in the log macros)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LogModule {
+    Mem,
+    Interrupts,
+    Descriptors,
+    Syscalls,
+    InitRamFs,
+    Modules,
+    /// Keep last, COUNT is derived from it
+    Ps2,
+}
+
+impl LogModule {
+    const COUNT: usize = Self::Ps2 as usize + 1;
+}
+
+/// Per module LogLevel, starts at config::LOG_LEVEL
+static LEVELS: [AtomicU8; LogModule::COUNT] = [const { AtomicU8::new(config::LOG_LEVEL as u8) }; LogModule::COUNT];
+
+pub fn set_level(module: LogModule, level: LogLevel) {
+    LEVELS[module as usize].store(level as u8, Ordering::Relaxed);
+}
+
+/// True if messages of level should be printed for module
+pub fn enabled(module: LogModule, level: LogLevel) -> bool {
+    LEVELS[module as usize].load(Ordering::Relaxed) >= level as u8
+}
+
+const LOG_RING_SIZE: usize = 16 * 1024;
+
+/// Fixed size byte ring, wrapping drops the oldest bytes
+struct LogRing {
+    data: [u8; LOG_RING_SIZE],
+    /// Next write position
+    end: usize,
+    len: usize,
+}
+
+impl LogRing {
+    /// Copies the newest bytes into buf, returns the count
+    fn read_recent(&self, buf: &mut [u8]) -> usize {
+        let count = self.len.min(buf.len());
+        let start = (self.end + LOG_RING_SIZE - count) % LOG_RING_SIZE;
+        let (first, second) = if start + count <= LOG_RING_SIZE {
+            (&self.data[start..start + count], &[][..])
+        } else {
+            (&self.data[start..], &self.data[..self.end])
+        };
+
+        buf[..first.len()].copy_from_slice(first);
+        buf[first.len()..count].copy_from_slice(second);
+
+        count
+    }
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Only the tail of oversized writes fits
+        let bytes = &s.as_bytes()[s.len().saturating_sub(LOG_RING_SIZE)..];
+        let first = bytes.len().min(LOG_RING_SIZE - self.end);
+
+        self.data[self.end..self.end + first].copy_from_slice(&bytes[..first]);
+        self.data[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+
+        self.end = (self.end + bytes.len()) % LOG_RING_SIZE;
+        self.len = (self.len + bytes.len()).min(LOG_RING_SIZE);
+
+        Ok(())
+    }
+}
+
 static COLORS: Mutex<(Color, Color)> = Mutex::new((Color(255, 255, 255), Color(0, 0, 0)));
+/// Recent log output for later retrieval
+/// LOCK SAFETY: ONLY LOCKED WITHOUT INTERRUPTS
+static RING: Mutex<LogRing> = Mutex::new(LogRing { data: [0; LOG_RING_SIZE], end: 0, len: 0 });
+/// Shared by all sinks so their timestamps line up
+static LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Line prefix for a log timestamp format
+pub struct Timestamp {
+    pub format: LogTimestamp,
+    pub ns: u64,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self { format: config::LOG_TIMESTAMP, ns: Time::boot_time_ns() }
+    }
+
+    /// Characters written for every format
+    pub const fn width(format: LogTimestamp) -> usize {
+        match format {
+            LogTimestamp::None => 0,
+            LogTimestamp::Boot => "[000.000] ".len(),
+            LogTimestamp::Wall => "[00:00:00.000] ".len(),
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            LogTimestamp::None => Ok(()),
+            LogTimestamp::Boot => {
+                let (seconds, millis) = boot_fields(self.ns);
+                write!(f, "[{:03}.{:03}] ", seconds, millis)
+            },
+            LogTimestamp::Wall => {
+                let (hours, minutes, seconds, millis) = wall_fields(time::wall_ns_of_day(self.ns));
+                write!(f, "[{:02}:{:02}:{:02}.{:03}] ", hours, minutes, seconds, millis)
+            },
+        }
+    }
+}
+
+/// Seconds (wrapping at 1000 to fit the width) and milliseconds since boot
+const fn boot_fields(ns: u64) -> (u64, u64) {
+    ((ns / 1000000000) % 1000, (ns / 1000000) % 1000)
+}
+
+/// Hours, minutes, seconds and milliseconds of a time of day
+const fn wall_fields(ns: u64) -> (u64, u64, u64, u64) {
+    (ns / 3600000000000, (ns / 60000000000) % 60, (ns / 1000000000) % 60, (ns / 1000000) % 1000)
+}
+
+#[allow(unused)]
+static STATIC_TIMESTAMP_CHECK: () = assert!(
+    Timestamp::width(LogTimestamp::None) == 0
+        && Timestamp::width(LogTimestamp::Boot) == 10
+        && Timestamp::width(LogTimestamp::Wall) == 15
+        && matches!(boot_fields(0), (0, 0))
+        && matches!(boot_fields(1_234_567_890_123), (234, 567))
+        && matches!(boot_fields(999_999_999), (0, 999))
+        && matches!(wall_fields(3_723_004_000_000), (1, 2, 3, 4))
+        && matches!(wall_fields(86_399_999_999_999), (23, 59, 59, 999))
+);
+
+/// Prefixes every line with a timestamp before handing it to the sinks
+struct TimestampWriter<F: Fn(Arguments) -> fmt::Result>(F);
+
+impl<F: Fn(Arguments) -> fmt::Result> Write for TimestampWriter<F> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if LINE_START.swap(false, Ordering::Relaxed) {
+                (self.0)(format_args!("{}", Timestamp::now()))?;
+            }
+
+            (self.0)(format_args!("{}", line))?;
+
+            if line.ends_with('\n') {
+                LINE_START.store(true, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+}
 
 impl Log {
     pub fn print(args: Arguments) -> fmt::Result {
-        SerialPrinter::print(args)?;
-        FramePrinter::print_default_static(args)
+        TimestampWriter(|args| {
+            Self::record(args);
+            SerialPrinter::print(args)?;
+            FramePrinter::print_default_static(args)
+        }).write_fmt(args)
+    }
+
+    /// Copies up to buf.len() of the most recent log bytes into buf, returns the count
+    pub fn read_recent(buf: &mut [u8]) -> usize {
+        without_interrupts(|| {
+            // AVOID DEADLOCK
+            RING.try_lock().map_or(0, |ring| ring.read_recent(buf))
+        })
+    }
+
+    /// Like read_recent but takes the ring even if it is locked (panic only)
+    pub fn emergency_read_recent(buf: &mut [u8]) -> usize {
+        // SAFETY: EMERGENCY (AND HOPEFULLY NO PROBLEM)
+        unsafe { RING.force_unlock() };
+        Self::read_recent(buf)
+    }
+
+    /// Prints raw already formatted (and timestamped) text to serial only
+    pub fn emergency_print_serial_raw(args: Arguments) -> fmt::Result {
+        SerialPrinter::emergency_print(args)
+    }
+
+    /// Appends to the log ring, dropped if it is busy
+    fn record(args: Arguments) {
+        without_interrupts(|| {
+            // AVOID DEADLOCK
+            if let Some(mut ring) = RING.try_lock() {
+                let _ = ring.write_fmt(args);
+            }
+        })
     }
 
     pub fn emergency_print(args: Arguments) -> fmt::Result {
         // SAFETY: EMERGENCY (AND HOPEFULLY NO PROBLEM)
         unsafe { COLORS.force_unlock() };
         let old = Self::swap_color((Color(255, 255, 255), Color(255, 0, 0)));
-        SerialPrinter::emergency_print(args)?;
-        FramePrinter::emergency_print_default_static(args)?;
+        TimestampWriter(|args| {
+            Self::record(args);
+            SerialPrinter::emergency_print(args)?;
+            FramePrinter::emergency_print_default_static(args)
+        }).write_fmt(args)?;
+        // SAFETY: EMERGENCY (AND HOPEFULLY NO PROBLEM)
+        unsafe { COLORS.force_unlock() };
+        let _ = Self::swap_color(old);
+
+        Ok(())
+    }
+
+    /// Does not touch the framebuffer, so it works even if the framebuffer code faulted
+    pub fn emergency_print_serial(args: Arguments) -> fmt::Result {
+        TimestampWriter(SerialPrinter::emergency_print).write_fmt(args)
+    }
+
+    pub fn emergency_print_screen(args: Arguments) -> fmt::Result {
+        // SAFETY: EMERGENCY (AND HOPEFULLY NO PROBLEM)
+        unsafe { COLORS.force_unlock() };
+        let old = Self::swap_color((Color(255, 255, 255), Color(255, 0, 0)));
+        TimestampWriter(FramePrinter::emergency_print_default_static).write_fmt(args)?;
         // SAFETY: EMERGENCY (AND HOPEFULLY NO PROBLEM)
         unsafe { COLORS.force_unlock() };
         let _ = Self::swap_color(old);
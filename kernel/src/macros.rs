@@ -15,54 +15,75 @@ macro_rules! _println {
 #[macro_export]
 macro_rules! print_init_msg {
     () => {{
-        let _ = $crate::_println!("Evos v{}-{} {} build {} UTC", ::core::env!("CARGO_PKG_VERSION"), ::core::env!("EVOS_BUILD_ID"), ::core::env!("EVOS_BUILD_PROFILE"), ::compile_time::datetime_str!());
-        if ::core::env!("EVOS_BUILD_PROFILE") == "debug" {
+        let _ = $crate::_println!("Evos v{}-{} {} build {} UTC", $crate::version::version(), $crate::version::build_id(), $crate::version::build_profile(), $crate::version::build_date());
+        if $crate::version::is_debug_build() {
             let _ = $crate::_println!("todo.txt says:");
             let _ = $crate::_println!("{}", $crate::initramfs::InitRamFs::open_text_file("todo.txt").unwrap_or(Ok("- Make todo.txt")).unwrap_or("- Make todo.txt"));
         }
     }};
 }
 
+#[macro_export]
+macro_rules! _log_line {
+    (($($color:expr),*), $prefix:literal, $($arg:tt)*) => {{
+        let color = $crate::log::Log::swap_color(($crate::text::format::Color($($color),*), $crate::text::format::Color(0, 0, 0)));
+        let _ = $crate::_println!("{}: {}", $prefix, ::core::format_args!($($arg)*));
+        let _ = $crate::log::Log::swap_color(color);
+    }};
+}
+
 #[macro_export]
 macro_rules! error {
+    (target: $module:expr, $($arg:tt)*) => {{
+        if $crate::log::enabled($module, $crate::config::LogLevel::Error) {
+            $crate::_log_line!((255, 0, 0), "ERROR", $($arg)*);
+        }
+    }};
     ($($arg:tt)*) => {{
         if $crate::config::LOG_LEVEL >= $crate::config::LogLevel::Error {
-            let color = $crate::log::Log::swap_color(($crate::text::format::Color(255, 0, 0), $crate::text::format::Color(0, 0, 0)));
-            let _ = $crate::_println!("ERROR: {}", ::core::format_args!($($arg)*));
-            let _ = $crate::log::Log::swap_color(color);
+            $crate::_log_line!((255, 0, 0), "ERROR", $($arg)*);
         }
     }};
 }
 
 #[macro_export]
 macro_rules! warn {
+    (target: $module:expr, $($arg:tt)*) => {{
+        if $crate::log::enabled($module, $crate::config::LogLevel::Warn) {
+            $crate::_log_line!((255, 255, 0), "WARN ", $($arg)*);
+        }
+    }};
     ($($arg:tt)*) => {{
         if $crate::config::LOG_LEVEL >= $crate::config::LogLevel::Warn {
-            let color = $crate::log::Log::swap_color(($crate::text::format::Color(255, 255, 0), $crate::text::format::Color(0, 0, 0)));
-            let _ = $crate::_println!("WARN : {}", ::core::format_args!($($arg)*));
-            let _ = $crate::log::Log::swap_color(color);
+            $crate::_log_line!((255, 255, 0), "WARN ", $($arg)*);
         }
     }};
 }
 
 #[macro_export]
 macro_rules! info {
+    (target: $module:expr, $($arg:tt)*) => {{
+        if $crate::log::enabled($module, $crate::config::LogLevel::Info) {
+            $crate::_log_line!((0, 255, 0), "INFO ", $($arg)*);
+        }
+    }};
     ($($arg:tt)*) => {{
         if $crate::config::LOG_LEVEL >= $crate::config::LogLevel::Info {
-            let color = $crate::log::Log::swap_color(($crate::text::format::Color(0, 255, 0), $crate::text::format::Color(0, 0, 0)));
-            let _ = $crate::_println!("INFO : {}", ::core::format_args!($($arg)*));
-            let _ = $crate::log::Log::swap_color(color);
+            $crate::_log_line!((0, 255, 0), "INFO ", $($arg)*);
         }
     }};
 }
 
 #[macro_export]
 macro_rules! debug {
+    (target: $module:expr, $($arg:tt)*) => {{
+        if $crate::log::enabled($module, $crate::config::LogLevel::Debug) {
+            $crate::_log_line!((128, 128, 255), "DEBUG", $($arg)*);
+        }
+    }};
     ($($arg:tt)*) => {{
         if $crate::config::LOG_LEVEL >= $crate::config::LogLevel::Debug {
-            let color = $crate::log::Log::swap_color(($crate::text::format::Color(128, 128, 255), $crate::text::format::Color(0, 0, 0)));
-            let _ = $crate::_println!("DEBUG: {}", ::core::format_args!($($arg)*));
-            let _ = $crate::log::Log::swap_color(color);
+            $crate::_log_line!((128, 128, 255), "DEBUG", $($arg)*);
         }
     }};
 }
@@ -3,12 +3,14 @@ use core::ops::Add;
 use bootloader_api::{config::Mapping, info::MemoryRegions};
 use phys::PageFrameAllocator;
 use spin::Mutex;
-use virt::GAlloc;
-use x86_64::{registers::control::Cr3, structures::paging::{OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, Size4KiB}, VirtAddr};
+use virt::{GAlloc, HeapConfig};
+use x86_64::{instructions::{interrupts::without_interrupts, tlb}, registers::{control::{Cr3, Cr4, Cr4Flags}, model_specific::{Efer, EferFlags}}, structures::paging::{frame::PhysFrameRange, mapper::{CleanUp, MapToError, UnmapError}, page::{PageRange, PageRangeInclusive}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB}, PhysAddr, VirtAddr};
 
-use crate::info;
+use crate::{config, debug, elf::{Elf, PF_W, PF_X, PT_LOAD}, info, warn};
 
 pub mod phys;
+pub mod pool;
+pub mod space;
 pub mod virt;
 
 pub const MIN_PHYSICAL_FREE: usize = 1024 * 1024 * 10; // 10 MiB
@@ -16,9 +18,50 @@ pub const OFFSET: u64 = 0xffff800000000000;
 pub const HEAP_VIRT_SIZE: usize = 1024 * 1024 * 1024 * 1; // 1 GiB
 pub const HEAP_VIRT_BASE: usize = 0usize.wrapping_sub(HEAP_VIRT_SIZE);
 pub const HEAP_BLOCK_SIZE: usize = 1024 * 1024 * 1; // 1 MiB
+pub const MAP_VIRT_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+pub const MAP_VIRT_BASE: usize = HEAP_VIRT_BASE.wrapping_sub(MAP_VIRT_SIZE);
+
+/// Level 4 entry reserved for user space
+pub const USER_L4_INDEX: usize = 42;
+pub const USER_VIRT_BASE: u64 = (USER_L4_INDEX as u64) << 39;
+pub const USER_VIRT_END: u64 = USER_VIRT_BASE + (1 << 39);
 
 pub const STACK_SIZE: usize = 100 * 1024;
 
+/// 4 KiB frames per 2 MiB page
+pub const HUGE_PAGE_FRAMES: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+/// Ranges larger than this (in pages) flush the whole TLB instead of every page
+pub const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// Kernel stack with a guard page directly below it
+#[repr(C, align(4096))]
+pub struct GuardedStack {
+    guard: [u8; Size4KiB::SIZE as usize],
+    stack: [u8; STACK_SIZE],
+}
+
+impl GuardedStack {
+    pub const fn new() -> Self {
+        Self { guard: [0; Size4KiB::SIZE as usize], stack: [0; STACK_SIZE] }
+    }
+
+    pub fn top(this: *const Self) -> VirtAddr {
+        VirtAddr::from_ptr(this) + size_of::<Self>() as u64
+    }
+
+    /// Unmaps the guard page so an overflow page faults instead of corrupting neighbouring statics
+    /// The frame is leaked, it belongs to the kernel image for static stacks and to PHYS_ALLOCATOR for allocated ones
+    pub fn protect(this: *const Self) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(this));
+
+        match VIRT_MAPPER.lock().as_mut().expect("Mapper missing!!!").unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(err) => warn!("Could not unmap stack guard page @ Virt 0x{:016x} ({:?})", page.start_address().as_u64(), err),
+        }
+    }
+}
+
 /// LOCK SAFETY: NOT USED IN KERNEL INTERRUPTS
 pub static PHYS_ALLOCATOR: Mutex<Option<PageFrameAllocator>> = Mutex::new(None);
 /// LOCK SAFETY: NOT USED IN KERNEL INTERRUPTS
@@ -34,6 +77,13 @@ macro_rules! palloc {
     };
 }
 
+#[macro_export]
+macro_rules! try_palloc {
+    () => {
+        ::x86_64::structures::paging::FrameAllocator::allocate_frame($crate::mem::PHYS_ALLOCATOR.lock().as_mut().expect("Allocator missing!!!"))
+    };
+}
+
 #[macro_export]
 macro_rules! palloc_loop {
     ($range:expr, $closure:expr) => {
@@ -63,6 +113,14 @@ macro_rules! pfree {
 
 #[macro_export]
 macro_rules! map {
+    ($page:expr, $frame:expr, $flags:expr) => {
+        $crate::try_map!($page, $frame, $flags).expect("Mapping failed!!!")
+    };
+}
+
+/// Like map! but returns the MapToError (PageAlreadyMapped, FrameAllocationFailed or ParentEntryHugePage) instead of panicking
+#[macro_export]
+macro_rules! try_map {
     ($page:expr, $frame:expr, $flags:expr) => {
         unsafe {
             ::x86_64::structures::paging::mapper::Mapper::map_to(
@@ -71,7 +129,7 @@ macro_rules! map {
                 $frame,
                 $flags,
                 $crate::mem::PHYS_ALLOCATOR.lock().as_mut().expect("Allocator missing!!!")
-            ).expect("Mapping failed!!!").flush()
+            ).map(|flush| flush.flush())
         }
     };
 }
@@ -133,11 +191,362 @@ macro_rules! remap {
     };
 }
 
+/// Maps every page to a new frame, returns false and undoes the partial mapping on OOM
+pub fn try_map_range(range: PageRange, flags: PageTableFlags) -> bool {
+    map_fresh(range, flags, false)
+}
+
+/// Like try_map_range but user accessible, frames are zeroed before mapping unless disabled in the config
+/// so no data of the kernel or a previous process leaks
+#[allow(dead_code)]
+pub fn try_map_user_range(range: PageRange, flags: PageTableFlags) -> bool {
+    map_fresh(range, flags | PageTableFlags::USER_ACCESSIBLE, config::mem::ZERO_USER_PAGES)
+}
+
+fn map_fresh(range: PageRange, flags: PageTableFlags, zero: bool) -> bool {
+    let mut mapper_guard = VIRT_MAPPER.lock();
+    let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+    let mut phys_guard = PHYS_ALLOCATOR.lock();
+    let phys = phys_guard.as_mut().expect("Allocator missing!!!");
+
+    for page in range {
+        // Frames zeroed on free are not cleared again
+        let mapped = if zero { phys.allocate_zeroed_frame() } else { phys.allocate_frame() };
+
+        let mapped = match mapped {
+            // SAFETY: CALLER OWNS THE UNUSED RANGE
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, phys) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                },
+                Err(MapToError::FrameAllocationFailed) => {
+                    // SAFETY: ALLOCATED ABOVE AND UNUSED
+                    unsafe { phys.deallocate_frame(frame) };
+                    false
+                },
+                Err(err) => panic!("Mapping failed!!! {:?}", err),
+            },
+            None => false,
+        };
+
+        if !mapped {
+            for mapped_page in Page::range(range.start, page) {
+                let (frame, flush) = mapper.unmap(mapped_page).expect("Unmapping failed!!!");
+                flush.flush();
+                // SAFETY: MAPPED ABOVE AND UNUSED
+                unsafe { phys.deallocate_frame(frame) };
+            }
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Maps len bytes of MMIO at phys uncached into the kernel mapping window, returns None if the window is exhausted
+/// Uses 2 MiB pages for large 2 MiB aligned regions
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Option<VirtAddr> {
+    // SAFETY: MMIO IS NOT RAM, SO NOTHING ELSE OWNS IT
+    let slice = unsafe { map_physical(phys, len, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE) }?;
+
+    Some(VirtAddr::from_ptr(slice.as_mut_ptr()))
+}
+
+/// Maps len bytes at phys with flags into the kernel mapping window, returns None if the window is exhausted
+/// Uses 2 MiB pages for large 2 MiB aligned regions, release with unmap_physical
+/// SAFETY: THE PHYSICAL RANGE MUST BE SAFE TO ACCESS WITH flags AND NOT ALIASED MUTABLY ELSEWHERE
+pub unsafe fn map_physical(phys: PhysAddr, len: usize, flags: PageTableFlags) -> Option<&'static mut [u8]> {
+    let frames = PhysFrame::<Size4KiB>::range(PhysFrame::containing_address(phys), PhysFrame::containing_address(phys + (len.max(1) - 1) as u64) + 1);
+    let count = frames.count();
+    let huge = count as u64 >= HUGE_PAGE_FRAMES && frames.start.start_address().is_aligned(Size2MiB::SIZE);
+    let pages = space::allocate(count, if huge { HUGE_PAGE_FRAMES as usize } else { 1 })?;
+
+    // SAFETY: PAGES ARE UNUSED WINDOW SPACE AND THE CALLER GUARANTEES THE FRAMES
+    unsafe { map_huge(pages.start.start_address(), frames.start.start_address(), count * Size4KiB::SIZE as usize, flags) };
+
+    let start = pages.start.start_address() + phys.as_u64() % Size4KiB::SIZE;
+
+    // SAFETY: MAPPED ABOVE AND ONLY REFERENCED HERE
+    Some(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len) })
+}
+
+/// Unmaps a range returned by map_physical and releases its window space, the frames stay untouched
+/// SAFETY: SLICE MUST HAVE BEEN RETURNED BY map_physical AND NOT BE USED AFTERWARDS
+#[allow(dead_code)]
+pub unsafe fn unmap_physical(slice: &'static mut [u8]) {
+    let start = VirtAddr::from_ptr(slice.as_ptr());
+    let pages = Page::<Size4KiB>::range(Page::containing_address(start), Page::containing_address(start + (slice.len().max(1) - 1) as u64) + 1);
+
+    // SAFETY: PAGES ARE UNUSED NOW AND THE FRAMES ARE NOT OWNED BY PHYS_ALLOCATOR
+    unsafe {
+        unmap_range(Page::range_inclusive(pages.start, pages.end - 1), false, false);
+        space::deallocate(pages);
+    }
+}
+
+/// Maps len bytes (rounded up to 4 KiB) of phys at virt, using 2 MiB pages wherever both are 2 MiB aligned and 4 KiB pages for the rest
+/// A 2 MiB chunk whose page table already exists is mapped with 4 KiB pages instead
+/// SAFETY: THE VIRTUAL RANGE MUST BE UNUSED AND THE PHYSICAL RANGE SAFE TO ACCESS WITH flags
+pub unsafe fn map_huge(virt: VirtAddr, phys: PhysAddr, len: usize, flags: PageTableFlags) {
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE), "Unaligned mapping {:?} -> {:?}!!!", virt, phys);
+
+    let mut mapper_guard = VIRT_MAPPER.lock();
+    let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+    let mut phys_guard = PHYS_ALLOCATOR.lock();
+    let phys_alloc = phys_guard.as_mut().expect("Allocator missing!!!");
+
+    let end = virt + (len as u64).next_multiple_of(Size4KiB::SIZE);
+    let (mut virt, mut phys) = (virt, phys);
+
+    while virt < end {
+        if virt.is_aligned(Size2MiB::SIZE) && phys.is_aligned(Size2MiB::SIZE) && end - virt >= Size2MiB::SIZE {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+
+            // SAFETY: CALLER GUARANTEES THE RANGES
+            if let Ok(flush) = unsafe { mapper.map_to(page, frame, flags, phys_alloc) } {
+                flush.flush();
+                virt += Size2MiB::SIZE;
+                phys += Size2MiB::SIZE;
+                continue;
+            }
+        }
+
+        let stop = if virt.is_aligned(Size2MiB::SIZE) { (virt + Size2MiB::SIZE).min(end) } else { virt.align_up(Size2MiB::SIZE).min(end) };
+
+        while virt < stop {
+            // SAFETY: CALLER GUARANTEES THE RANGES
+            unsafe { mapper.map_to(Page::<Size4KiB>::containing_address(virt), PhysFrame::containing_address(phys), flags, phys_alloc) }.expect("Mapping failed!!!").flush();
+            virt += Size4KiB::SIZE;
+            phys += Size4KiB::SIZE;
+        }
+    }
+}
+
+/// Flushes the whole TLB including global entries
+pub fn flush_all() {
+    without_interrupts(|| {
+        let flags = Cr4::read();
+        // SAFETY: ONLY TOGGLES PGE WHICH FLUSHES ALL ENTRIES
+        unsafe {
+            Cr4::write(flags ^ Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(flags);
+        }
+    })
+}
+
+/// Flushes every page of range or the whole TLB if the range is larger than FLUSH_ALL_THRESHOLD
+pub fn flush_range(range: PageRange) {
+    if flushes_all(range.end - range.start) {
+        flush_all();
+    } else {
+        range.for_each(|page| tlb::flush(page.start_address()));
+    }
+}
+
+/// True if flushing pages one by one costs more than reloading the whole TLB
+const fn flushes_all(pages: u64) -> bool {
+    pages > FLUSH_ALL_THRESHOLD
+}
+
+#[allow(unused)]
+static STATIC_FLUSHES_ALL_CHECK: () = assert!(
+    !flushes_all(0) && !flushes_all(1) && !flushes_all(FLUSH_ALL_THRESHOLD) && flushes_all(FLUSH_ALL_THRESHOLD + 1)
+);
+
+/// Unmaps every page and frees the backing frames
+/// SAFETY: PAGES MUST BE MAPPED TO FRAMES OF PHYS_ALLOCATOR AND LATER UNUSED
+pub unsafe fn unmap_range_free(range: PageRange) {
+    let mut mapper_guard = VIRT_MAPPER.lock();
+    let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+    let mut phys_guard = PHYS_ALLOCATOR.lock();
+    let phys = phys_guard.as_mut().expect("Allocator missing!!!");
+
+    // Physically contiguous runs are freed together
+    let mut run: Option<PhysFrameRange> = None;
+
+    for page in range {
+        let (frame, flush) = mapper.unmap(page).expect("Unmapping failed!!!");
+        // Flushed below (freed frames can not be reused before that since the allocator stays locked)
+        flush.ignore();
+
+        run = match run {
+            Some(current) if current.end == frame => Some(PhysFrame::range(current.start, frame + 1)),
+            Some(current) => {
+                // SAFETY: FRAMES ARE NO LONGER MAPPED AND UNUSED
+                unsafe { phys.deallocate_range(current) };
+                Some(PhysFrame::range(frame, frame + 1))
+            },
+            None => Some(PhysFrame::range(frame, frame + 1)),
+        };
+    }
+
+    if let Some(current) = run {
+        // SAFETY: FRAMES ARE NO LONGER MAPPED AND UNUSED
+        unsafe { phys.deallocate_range(current) };
+    }
+
+    flush_range(range);
+}
+
+/// Unmaps every page in range, skipping pages that are not mapped with a warning, 2 MiB pages overlapping range are unmapped whole
+/// Frees the backing frames if free_frames is set and empty page tables if clean_up is set
+/// SAFETY: THE RANGE MUST NOT BE IN USE AND THE FRAMES MUST BE UNUSED IF free_frames IS SET
+#[allow(dead_code)]
+pub unsafe fn unmap_range(range: PageRangeInclusive, free_frames: bool, clean_up: bool) {
+    if range.is_empty() {
+        return;
+    }
+
+    let mut mapper_guard = VIRT_MAPPER.lock();
+    let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+    let mut phys_guard = PHYS_ALLOCATOR.lock();
+    let phys = phys_guard.as_mut().expect("Allocator missing!!!");
+
+    let mut page = range.start;
+    // 2 MiB pages may extend past range
+    let mut huge_unmapped = false;
+
+    loop {
+        // Flushed below (freed frames can not be reused before that since the allocator stays locked)
+        let next = match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.ignore();
+                if free_frames {
+                    // SAFETY: FRAME IS NO LONGER MAPPED AND UNUSED
+                    unsafe { phys.deallocate_frame(frame) };
+                }
+                page + 1
+            },
+            Err(UnmapError::ParentEntryHugePage) => {
+                let huge = Page::<Size2MiB>::containing_address(page.start_address());
+                let (frame, flush) = mapper.unmap(huge).expect("Unmapping failed!!!");
+                flush.ignore();
+                huge_unmapped = true;
+                if free_frames {
+                    let start = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+                    // SAFETY: FRAMES ARE NO LONGER MAPPED AND UNUSED
+                    unsafe { phys.deallocate_range(PhysFrame::range(start, start + HUGE_PAGE_FRAMES)) };
+                }
+                Page::containing_address(huge.start_address() + Size2MiB::SIZE)
+            },
+            Err(error) => {
+                warn!("Skipping unmap of {:?}: {:?}", page, error);
+                page + 1
+            },
+        };
+
+        if next > range.end || next <= page {
+            break;
+        }
+
+        page = next;
+    }
+
+    if clean_up {
+        // SAFETY: THE WHOLE RANGE IS UNMAPPED
+        unsafe { mapper.clean_up_addr_range(range, phys) };
+    }
+
+    if huge_unmapped {
+        flush_all();
+    } else {
+        flush_range(Page::range(range.start, range.end + 1));
+    }
+}
+
+/// Pages completely inside start..end, partial pages may be shared with a neighbouring segment
+pub fn inner_pages(start: u64, end: u64) -> Option<PageRange> {
+    VirtAddr::try_new(start).ok()?;
+    VirtAddr::try_new(end).ok()?;
+
+    let (start, end) = inner_page_bounds(start, end)?;
+
+    Some(Page::range(Page::containing_address(VirtAddr::try_new(start).ok()?), Page::containing_address(VirtAddr::try_new(end).ok()?)))
+}
+
+/// Page aligned start and end of the pages completely inside start..end, None if there are none
+const fn inner_page_bounds(start: u64, end: u64) -> Option<(u64, u64)> {
+    let Some(start) = start.checked_next_multiple_of(Size4KiB::SIZE) else {
+        return None;
+    };
+    let end = end - end % Size4KiB::SIZE;
+
+    if start < end { Some((start, end)) } else { None }
+}
+
+#[allow(unused)]
+static STATIC_INNER_PAGE_BOUNDS_CHECK: () = assert!(
+    matches!(inner_page_bounds(0x1000, 0x3000), Some((0x1000, 0x3000)))
+        && matches!(inner_page_bounds(0x1001, 0x3fff), Some((0x2000, 0x3000)))
+        && matches!(inner_page_bounds(0x0fff, 0x2001), Some((0x1000, 0x2000)))
+        && inner_page_bounds(0x1001, 0x1fff).is_none()
+        && inner_page_bounds(0x1000, 0x1fff).is_none()
+        && inner_page_bounds(0x1001, 0x2fff).is_none()
+        && inner_page_bounds(0x2000, 0x2000).is_none()
+        && inner_page_bounds(0x3000, 0x1000).is_none()
+        && inner_page_bounds(u64::MAX, u64::MAX).is_none()
+);
+
+/// Drops WRITABLE from the read only kernel segments (text stays executable, rodata gets NO_EXECUTE)
+/// Only the flags are updated so the running code never gets unmapped
+pub fn protect_kernel(kernel_addr: u64, kernel_len: u64, image_offset: u64) {
+    // SAFETY: GUARANTEED BY BOOTLOADER AND PHYSICAL MEMORY IS MAPPED AT OFFSET
+    let raw = unsafe { core::slice::from_raw_parts((kernel_addr + OFFSET) as *const u8, kernel_len as usize) };
+
+    let Some(elf) = Elf::new(raw) else {
+        warn!("Kernel image is not an ELF64 file, leaving it writable");
+        return;
+    };
+
+    // SAFETY: NX ONLY RESTRICTS
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    for header in elf.program_headers().filter(|header| header.kind == PT_LOAD && header.flags & PF_W == 0) {
+        let start = image_offset + header.vaddr;
+        let Some(pages) = inner_pages(start, start + header.mem_size) else {
+            continue;
+        };
+
+        let executable = header.flags & PF_X != 0;
+        let flags = if executable {
+            PageTableFlags::PRESENT | PageTableFlags::GLOBAL
+        } else {
+            PageTableFlags::PRESENT | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE
+        };
+
+        {
+            let mut mapper_guard = VIRT_MAPPER.lock();
+            let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+
+            for page in pages {
+                // SAFETY: ONLY REMOVES PERMISSIONS THE SEGMENT DOES NOT HAVE
+                match unsafe { mapper.update_flags(page, flags) } {
+                    // Flushed below
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => warn!("Could not protect kernel page @ Virt 0x{:016x} ({:?})", page.start_address().as_u64(), err),
+                }
+            }
+        }
+
+        flush_range(pages);
+
+        debug!("Kernel segment @ Virt 0x{:016x}-0x{:016x} mapped {}", pages.start.start_address().as_u64(), pages.end.start_address().as_u64(), if executable { "R+X" } else { "R" });
+    }
+}
+
 /// SAFETY: MEMORY REGIONS MUST BE VALID AND LATER UNUSED
 pub unsafe fn init(memory_regions: &mut MemoryRegions) {
     // SAFETY: MEMORY REGIONS ARE VALID AND LATER UNUSED
     *PHYS_ALLOCATOR.lock() = Some(unsafe { PageFrameAllocator::new(memory_regions) });
 
+    if config::mem::MEMTEST {
+        info!("Testing 1 in {} free physical frames...", config::mem::MEMTEST_STRIDE);
+        let bad = PHYS_ALLOCATOR.lock().as_mut().unwrap().memory_test(config::mem::MEMTEST_STRIDE);
+        info!("Memory test complete ({} bad frames)", bad);
+    }
+
     // SAFETY: l4table IS ONLY CALLED HERE AND IS VALID (OFFSET IS ALSO VALID)
     *VIRT_MAPPER.lock() = Some(unsafe { OffsetPageTable::new(l4table(), VirtAddr::from_ptr(OFFSET as *const ())) });
 
@@ -157,14 +566,16 @@ pub unsafe fn init(memory_regions: &mut MemoryRegions) {
         // Reserved for kernel heap
         assert!(mapper.level_4_table().iter().skip(start4.into()).take(usize::from(end4) - usize::from(start4)).all(|entry| entry.flags().intersects(PageTableFlags::PRESENT)), "Level 4 entry present in Kernel Heap!!!");
 
-        //TODO: MAKE CONST
         // Reserved for user
-        assert!(mapper.level_4_table()[42].is_unused())
+        assert!(mapper.level_4_table()[USER_L4_INDEX].is_unused())
         //TODO: THIS
         //mapper.level_4_table().iter().find(|e| e.is_unused());
     }
 
-    VIRT_ALLOCATOR.init();
+    VIRT_ALLOCATOR.init(HeapConfig::default());
+
+    // Early boot frames never leave the kernel, so zeroing only starts here
+    PHYS_ALLOCATOR.lock().as_mut().unwrap().set_zero_on_free(config::mem::ZERO_FREED_FRAMES);
 
     let size = PHYS_ALLOCATOR.lock().as_ref().unwrap().size();
     let free = PHYS_ALLOCATOR.lock().as_ref().unwrap().free();
@@ -177,13 +588,127 @@ pub const CONFIG: bootloader_api::BootloaderConfig = {
     config.kernel_stack_size = STACK_SIZE as u64;
     config.mappings.physical_memory = Some(Mapping::FixedAddress(OFFSET));
     config.mappings.dynamic_range_start = Some(OFFSET);
-    config.mappings.dynamic_range_end = Some(0u64.wrapping_sub(HEAP_VIRT_SIZE as u64));
+    config.mappings.dynamic_range_end = Some(MAP_VIRT_BASE as u64);
     config
 };
 
+/// True if ptr..ptr + len lies inside the user region (and so below OFFSET), does not check the mapping
+pub fn is_user_range(ptr: usize, len: usize) -> bool {
+    (ptr as u64) >= USER_VIRT_BASE && (ptr as u64).checked_add(len as u64).is_some_and(|end| end <= USER_VIRT_END)
+}
+
+/// Checks that every page of ptr..ptr + len is present and user accessible in the active page table
+/// Does not check WRITABLE, so writes can still fault on read only pages
+/// SAFETY: THE SLICE MUST NOT BE USED AFTER THE ADDRESS SPACE CHANGES OR THE PAGES ARE UNMAPPED
+pub unsafe fn validate_user_slice<'a>(ptr: usize, len: usize) -> Option<&'a mut [u8]> {
+    if len == 0 || !is_user_range(ptr, len) {
+        return None;
+    }
+
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(VirtAddr::new(ptr as u64)),
+        Page::containing_address(VirtAddr::new((ptr + len - 1) as u64)),
+    );
+
+    let mapper_guard = VIRT_MAPPER.lock();
+    let mapper = mapper_guard.as_ref().expect("Mapper missing!!!");
+
+    for page in pages {
+        // USER_ACCESSIBLE has to be set on every level
+        if !walk(mapper.level_4_table(), page.start_address()).is_some_and(|(_, flags)| flags.contains(PageTableFlags::USER_ACCESSIBLE)) {
+            return None;
+        }
+    }
+
+    // SAFETY: EVERY PAGE IS MAPPED FOR USER SPACE
+    Some(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+}
+
+/// Physical address and effective flags behind addr in the active page table, None if unmapped
+/// WRITABLE and USER_ACCESSIBLE are only reported if every level sets them, NO_EXECUTE if any level does
+#[allow(dead_code)]
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let mapper_guard = VIRT_MAPPER.lock();
+
+    walk(mapper_guard.as_ref().expect("Mapper missing!!!").level_4_table(), addr)
+}
+
+fn walk(level_4_table: &PageTable, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table = level_4_table;
+    let mut effective = inherited;
+
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &table[index];
+        let flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        effective &= flags | !inherited;
+        effective |= flags & PageTableFlags::NO_EXECUTE;
+
+        // 1 GiB or 2 MiB page
+        if (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) || level == 3 {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+
+            return Some((entry.addr() + addr.as_u64() % page_size, (flags - inherited) | effective));
+        }
+
+        // SAFETY: PRESENT TABLES ARE MAPPED AT OFFSET
+        table = unsafe { &*((entry.addr().as_u64() + OFFSET) as *const PageTable) };
+    }
+
+    unreachable!()
+}
+
+/// Usable physical memory in bytes (0 before init)
+#[allow(dead_code)]
+pub fn total_physical() -> usize {
+    PHYS_ALLOCATOR.lock().as_ref().map_or(0, PageFrameAllocator::size)
+}
+
+/// Free physical memory in bytes (0 before init)
+#[allow(dead_code)]
+pub fn free_physical() -> usize {
+    PHYS_ALLOCATOR.lock().as_ref().map_or(0, PageFrameAllocator::free_cached)
+}
+
+/// Runs callback once free physical memory drops below threshold bytes (again after it recovered)
+/// The callback runs inside the frame allocator, so it must not allocate or free frames (log or set a flag instead)
+#[allow(dead_code)]
+pub fn on_low_memory(threshold: usize, callback: fn()) {
+    PHYS_ALLOCATOR.lock().as_mut().expect("Allocator missing!!!").set_low_memory_callback(threshold, callback);
+}
+
+/// Level 4 table frame of the active address space
+pub fn current_address_space() -> PhysFrame {
+    Cr3::read().0
+}
+
+pub fn is_current_address_space(root: PhysFrame) -> bool {
+    current_address_space() == root
+}
+
+/// Loads root into CR3 unless it is already active (avoiding the TLB flush), returns true if CR3 was reloaded
+/// SAFETY: ROOT MUST BE A VALID LEVEL 4 TABLE THAT MAPS THE KERNEL
+#[allow(dead_code)]
+pub unsafe fn switch_address_space(root: PhysFrame) -> bool {
+    if is_current_address_space(root) {
+        return false;
+    }
+
+    // SAFETY: ROOT IS VALID AND MAPS THE KERNEL
+    unsafe { Cr3::write(root, Cr3::read().1) };
+
+    true
+}
+
 /// SAFETY: REFERENCE MUST BE USED WITH PAGE TABLE AND MULTIPLE MUTABLE REFERENCES IN MIND
 unsafe fn l4table() -> &'static mut PageTable {
     // SAFETY: PAGE TABLE IS VALID (OTHERWISE A PAGE FAULT WOULD HAVE TRIPLE FAULTED ALREADY)
-    unsafe { &mut *(Cr3::read().0.start_address().as_u64().add(OFFSET) as *mut PageTable) }
+    unsafe { &mut *(current_address_space().start_address().as_u64().add(OFFSET) as *mut PageTable) }
 }
 
@@ -1,16 +1,18 @@
-use core::{mem::MaybeUninit, ops::{Deref, DerefMut}, slice};
+use core::{mem::MaybeUninit, ops::{Deref, DerefMut, Range}, slice};
 
 use bitvec::slice::BitSlice;
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
 use x86_64::{structures::paging::{frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB}, PhysAddr, VirtAddr};
 
-use crate::debug;
+use crate::{debug, error};
 
 use super::OFFSET;
 
 struct SingleRegionPageFrameAllocator<'a> {
     frames: PhysFrameRange,
     bitmap: &'a mut BitSlice<u8>,
+    /// Set for free frames that were zeroed on deallocation, boot time free frames are never known clean
+    clean: &'a mut BitSlice<u8>,
     next_free: Option<usize>,
 }
 
@@ -23,17 +25,22 @@ impl SingleRegionPageFrameAllocator<'static> {
         let start = VirtAddr::new(region.start + OFFSET);
         let size_in_pages = ((region.end - region.start) / Size4KiB::SIZE) as usize;
         let slice_size = size_in_pages / 8;
-        let offset = (size_of::<SingleRegionPageFrameAllocator>() + slice_size + Size4KiB::SIZE as usize - 1) / Size4KiB::SIZE as usize;
+        let offset = (size_of::<SingleRegionPageFrameAllocator>() + 2 * slice_size + Size4KiB::SIZE as usize - 1) / Size4KiB::SIZE as usize;
         let this = start.as_mut_ptr::<MaybeUninit<Self>>();
         // SAFETY: OFFSET AND THIS IMPLEMENTATION GUARANTEES THAT THIS SLICE IS MAPPED AND UNIQUE
         let slice = unsafe { slice::from_raw_parts_mut(this.add(1).cast(), slice_size) };
         let bitmap = BitSlice::from_slice_mut(slice);
         bitmap.fill(false);
+        // SAFETY: OFFSET AND THIS IMPLEMENTATION GUARANTEES THAT THIS SLICE IS MAPPED AND UNIQUE
+        let slice = unsafe { slice::from_raw_parts_mut(this.add(1).cast::<u8>().add(slice_size), slice_size) };
+        let clean = BitSlice::from_slice_mut(slice);
+        clean.fill(false);
 
         // SAFETY: MEMORYREGION IS VALID AND USABLE
         let this = (unsafe { &mut *this }).write(SingleRegionPageFrameAllocator {
             next_free: None,
             bitmap,
+            clean,
             frames: PhysFrame::range(PhysFrame::containing_address(PhysAddr::new(region.start)), PhysFrame::containing_address(PhysAddr::new(region.end)))
         });
         
@@ -45,15 +52,30 @@ impl SingleRegionPageFrameAllocator<'static> {
     }
 
     fn allocate(&mut self) -> Option<PhysFrame> {
+        self.allocate_clean().map(|(frame, _)| frame)
+    }
+
+    /// Also returns whether the frame is known to be zeroed
+    fn allocate_clean(&mut self) -> Option<(PhysFrame, bool)> {
         self.next_free.map(|this| {
             self.bitmap.set(this, true);
+            let clean = self.clean.replace(this, false);
             self.next_free = self.bitmap[this..].first_zero().map(|val| val + this);
-            PhysFrame::from_start_address(PhysAddr::new(self.frames.start.start_address().as_u64() + Size4KiB::SIZE * this as u64)).unwrap()
+            (PhysFrame::from_start_address(PhysAddr::new(self.frames.start.start_address().as_u64() + Size4KiB::SIZE * this as u64)).unwrap(), clean)
         })
     }
 
+    /// Only allocates if the lowest free frame starts below limit
+    fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        match self.next_free {
+            Some(index) if self.frames.start.start_address().as_u64() + Size4KiB::SIZE * (index as u64) < limit.as_u64() => self.allocate(),
+            _ => None,
+        }
+    }
+
     /// Returns true if page was deallocated, panics if page is deallocated already
-    fn deallocate(&mut self, frame: PhysFrame) -> bool {
+    /// Clean marks the frame as zeroed
+    fn deallocate(&mut self, frame: PhysFrame, clean: bool) -> bool {
         let start = self.frames.start.start_address().as_u64();
         let end = self.frames.end.start_address().as_u64();
         let frame = frame.start_address().as_u64();
@@ -64,6 +86,7 @@ impl SingleRegionPageFrameAllocator<'static> {
                 panic!("Invalid frame index {} for region @ Phys 0x{:016x} deallocated in SingleRegionPageFrameAllocator!!!", index, start)
             } else {
                 self.bitmap.set(index, false);
+                self.clean.set(index, clean);
                 match self.next_free {
                     Some(old) => if old > index { self.next_free = Some(index) },
                     None => self.next_free = Some(index),
@@ -75,6 +98,96 @@ impl SingleRegionPageFrameAllocator<'static> {
         }
     }
 
+    /// Bitmap indices of the part of range inside this region
+    fn indices(&self, range: PhysFrameRange) -> Range<usize> {
+        let start = range.start.max(self.frames.start);
+        let end = range.end.min(self.frames.end);
+
+        if start >= end {
+            return 0..0;
+        }
+
+        // Frames past the bitmap are never handed out
+        let first = (start - self.frames.start) as usize;
+        let last = ((end - self.frames.start) as usize).min(self.bitmap.len());
+        first..last.max(first)
+    }
+
+    /// Returns the number of frames of range inside this region, panics if one of them is free (double free)
+    fn check_allocated(&self, range: PhysFrameRange) -> usize {
+        let indices = self.indices(range);
+
+        if let Some(index) = self.bitmap[indices.clone()].first_zero() {
+            panic!("Invalid frame index {} for region @ Phys 0x{:016x} deallocated in SingleRegionPageFrameAllocator!!!", index + indices.start, self.frames.start.start_address().as_u64())
+        }
+
+        indices.len()
+    }
+
+    /// Frees the part of range inside this region, returns the number of frames freed, panics on double free
+    /// Clean marks the frames as zeroed
+    fn deallocate_range(&mut self, range: PhysFrameRange, clean: bool) -> usize {
+        self.check_allocated(range);
+
+        let indices = self.indices(range);
+        let first = indices.start;
+        let bits = &mut self.bitmap[indices.clone()];
+
+        bits.fill(false);
+        self.clean[indices].fill(clean);
+
+        match self.next_free {
+            Some(old) if old <= first => (),
+            _ => if !bits.is_empty() { self.next_free = Some(first) },
+        }
+
+        bits.len()
+    }
+
+    /// Returns true if frame is part of this region and marks it as allocated
+    fn reserve(&mut self, frame: PhysFrame) -> bool {
+        let start = self.frames.start.start_address().as_u64();
+        let end = self.frames.end.start_address().as_u64();
+        let frame = frame.start_address().as_u64();
+
+        // Frames past the bitmap are never handed out
+        if start <= frame && frame < end && (((frame - start) / Size4KiB::SIZE) as usize) < self.bitmap.len() {
+            let index = ((frame - start) / Size4KiB::SIZE) as usize;
+            self.bitmap.set(index, true);
+            self.clean.set(index, false);
+            if self.next_free == Some(index) {
+                self.next_free = self.bitmap[index..].first_zero().map(|val| val + index);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Tests every stride-th free frame and marks failing frames as allocated, returns the number of bad frames
+    fn memory_test(&mut self, stride: usize) -> usize {
+        let start = self.frames.start.start_address().as_u64();
+        let mut bad = 0;
+        let mut index = self.bitmap.first_zero();
+
+        while let Some(current) = index {
+            let frame = start + Size4KiB::SIZE * current as u64;
+
+            // SAFETY: FRAME IS FREE AND MAPPED AT OFFSET
+            if !unsafe { test_frame(frame) } {
+                error!("Memory test failed for frame @ Phys 0x{:016x}", frame);
+                self.bitmap.set(current, true);
+                bad += 1;
+            }
+
+            index = self.bitmap[current + 1..].iter_zeros().nth(stride - 1).map(|next| next + current + 1);
+        }
+
+        self.next_free = self.bitmap.first_zero();
+
+        bad
+    }
+
     fn size(&self) -> usize {
         self.frames.size() as usize
     }
@@ -84,6 +197,22 @@ impl SingleRegionPageFrameAllocator<'static> {
     }
 }
 
+/// Writes and reads back test patterns, returns false if any pattern did not survive
+/// SAFETY: FRAME MUST BE UNUSED
+unsafe fn test_frame(frame: u64) -> bool {
+    const PATTERNS: [u64; 4] = [0xffff_ffff_ffff_ffff, 0xaaaa_aaaa_aaaa_aaaa, 0x5555_5555_5555_5555, 0x0000_0000_0000_0000];
+    const WORDS: usize = Size4KiB::SIZE as usize / size_of::<u64>();
+
+    let page = VirtAddr::new(frame + OFFSET).as_mut_ptr::<u64>();
+
+    PATTERNS.iter().all(|&pattern| {
+        // SAFETY: FRAME IS UNUSED AND MAPPED AT OFFSET
+        (0..WORDS).for_each(|word| unsafe { page.add(word).write_volatile(pattern) });
+        // SAFETY: FRAME IS UNUSED AND MAPPED AT OFFSET
+        (0..WORDS).all(|word| unsafe { page.add(word).read_volatile() } == pattern)
+    })
+}
+
 /// Static SingleRegionPageFrameAllocator padded reference holder that has the same size as MemoryRegion.
 struct SSRPFAReferenceStruct {
     raw: &'static mut SingleRegionPageFrameAllocator<'static>,
@@ -115,6 +244,14 @@ impl DerefMut for SSRPFAReferenceStruct {
 
 pub struct PageFrameAllocator {
     allocators: &'static mut [SSRPFAReferenceStruct],
+    /// Clear frames on deallocation so old contents never leak into a new owner
+    zero_on_free: bool,
+    /// Cached free frame count, so the low memory check stays cheap
+    free_frames: usize,
+    /// Threshold in frames and the callback run once free frames drop below it
+    low_memory: Option<(usize, fn())>,
+    /// Set while below the threshold so the callback runs once per drop
+    low_memory_reported: bool,
 }
 
 impl PageFrameAllocator {
@@ -165,11 +302,79 @@ impl PageFrameAllocator {
         // SAFETY: SLICE IS ALREADY VALID AND INITIALIZED
         let raw = unsafe { slice::from_raw_parts_mut(raw.as_mut_ptr().cast::<SSRPFAReferenceStruct>(), raw.len()) };
 
-        Self {
+        let mut this = Self {
             allocators: raw,
+            zero_on_free: false,
+            free_frames: 0,
+            low_memory: None,
+            low_memory_reported: false,
+        };
+        this.recount();
+        this
+    }
+
+    /// Recomputes the cached free frame count from the bitmaps
+    fn recount(&mut self) {
+        self.free_frames = self.free() / Size4KiB::SIZE as usize;
+    }
+
+    /// Runs callback (with this allocator locked, so it must not allocate or free frames) once free memory drops below threshold bytes
+    /// Rearmed once free memory is above the threshold again
+    pub fn set_low_memory_callback(&mut self, threshold: usize, callback: fn()) {
+        self.low_memory = Some((threshold / Size4KiB::SIZE as usize, callback));
+        self.low_memory_reported = false;
+        self.check_low_memory();
+    }
+
+    fn check_low_memory(&mut self) {
+        if let Some((threshold, callback)) = self.low_memory {
+            match (self.free_frames < threshold, self.low_memory_reported) {
+                (true, false) => {
+                    self.low_memory_reported = true;
+                    callback();
+                },
+                (false, true) => self.low_memory_reported = false,
+                _ => (),
+            }
         }
     }
 
+    /// Free bytes from the cached frame count (free walks the bitmaps)
+    pub fn free_cached(&self) -> usize {
+        self.free_frames * Size4KiB::SIZE as usize
+    }
+
+    /// Zeroing has a cost, so it is off until enabled (e.g. once user space shares the pool)
+    /// Frames zeroed on free are remembered, so allocate_zeroed_frame does not clear them a second time
+    pub fn set_zero_on_free(&mut self, enabled: bool) {
+        self.zero_on_free = enabled;
+    }
+
+    /// Returns true if the frames were zeroed
+    /// SAFETY: FRAMES MUST BE UNUSED
+    unsafe fn zero(&self, range: PhysFrameRange) -> bool {
+        if self.zero_on_free {
+            // SAFETY: FRAMES ARE MAPPED AT OFFSET AND UNUSED
+            unsafe { ((range.start.start_address().as_u64() + OFFSET) as *mut u8).write_bytes(0, (range.count() as u64 * Size4KiB::SIZE) as usize) };
+        }
+
+        self.zero_on_free
+    }
+
+    /// Allocates a zeroed frame, only clearing it if it was not already zeroed on free
+    pub fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        let (frame, clean) = self.allocators.iter_mut().find_map(|allocator| allocator.allocate_clean())?;
+        self.free_frames -= 1;
+        self.check_low_memory();
+
+        if !clean {
+            // SAFETY: FRAME IS UNUSED AND MAPPED AT OFFSET
+            unsafe { VirtAddr::new(frame.start_address().as_u64() + OFFSET).as_mut_ptr::<u8>().write_bytes(0, Size4KiB::SIZE as usize) };
+        }
+
+        Some(frame)
+    }
+
     pub fn size(&self) -> usize {
         self.allocators.iter().fold(0, |acc, allocator| acc + allocator.size())
     }
@@ -177,19 +382,91 @@ impl PageFrameAllocator {
     pub fn free(&self) -> usize {
         self.allocators.iter().fold(0, |acc, allocator| acc + allocator.free())
     }
+
+    /// Tests every stride-th free frame and permanently reserves bad ones, returns the number of bad frames
+    pub fn memory_test(&mut self, stride: usize) -> usize {
+        let bad = self.allocators.iter_mut().fold(0, |acc, allocator| acc + allocator.memory_test(stride));
+        self.recount();
+        bad
+    }
+
+    /// Panics if a frame of range is free or not part of any region, before anything is zeroed
+    fn check_allocated(&self, range: PhysFrameRange) {
+        let owned = self.allocators.iter().fold(0, |acc, allocator| acc + allocator.check_allocated(range));
+
+        if owned as u64 != range.count() as u64 {
+            panic!("Invalid frames @ Phys 0x{:016x}-0x{:016x} deallocated in PageFrameAllocator!!!", range.start.start_address().as_u64(), range.end.start_address().as_u64());
+        }
+    }
+
+    /// Frees a contiguous range in one pass per region instead of one search per frame
+    /// SAFETY: EVERY FRAME MUST HAVE BEEN ALLOCATED BY THIS ALLOCATOR AND BE UNUSED
+    pub unsafe fn deallocate_range(&mut self, range: PhysFrameRange) {
+        self.check_allocated(range);
+
+        // SAFETY: FRAMES ARE UNUSED AND OWNED BY THIS ALLOCATOR
+        let clean = unsafe { self.zero(range) };
+
+        let freed = self.allocators.iter_mut().fold(0, |acc, allocator| acc + allocator.deallocate_range(range, clean));
+
+        self.free_frames += freed;
+        self.check_low_memory();
+    }
+
+    /// Permanently marks frame as allocated, returns false if it is not part of a usable region (and never handed out anyway)
+    /// Frames allocated before are reserved as well and must not be deallocated afterwards
+    #[allow(dead_code)]
+    pub fn reserve_frame(&mut self, frame: PhysFrame) -> bool {
+        let reserved = self.allocators.iter_mut().any(|allocator| allocator.reserve(frame));
+        // The frame may or may not have been free before
+        self.recount();
+        self.check_low_memory();
+        reserved
+    }
+
+    /// Permanently marks every frame in range as allocated, returns the number of frames inside usable regions
+    #[allow(dead_code)]
+    pub fn reserve_range(&mut self, range: PhysFrameRange) -> usize {
+        let reserved = range.filter(|&frame| self.allocators.iter_mut().any(|allocator| allocator.reserve(frame))).count();
+        self.recount();
+        self.check_low_memory();
+        reserved
+    }
+
+    /// Allocates a frame that starts below limit (e.g. 0x1_0000_0000 for 32 bit DMA)
+    #[allow(dead_code)]
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let frame = self.allocators.iter_mut()
+            .filter(|allocator| allocator.frames.start.start_address() < limit)
+            .find_map(|allocator| allocator.allocate_below(limit))?;
+        self.free_frames -= 1;
+        self.check_low_memory();
+        Some(frame)
+    }
 }
 
 // SAFETY: THE ALLOCATOR SHOULD BE SAFE
 unsafe impl FrameAllocator<Size4KiB> for PageFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        self.allocators.iter_mut().find_map(|allocator| allocator.allocate())
+        let frame = self.allocators.iter_mut().find_map(|allocator| allocator.allocate())?;
+        self.free_frames -= 1;
+        self.check_low_memory();
+        Some(frame)
     }
 }
 
 impl FrameDeallocator<Size4KiB> for PageFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
-        match self.allocators.iter_mut().find_map(|allocator| if allocator.deallocate(frame) { Some(()) } else { None } ) {
-            Some(_) => (),
+        self.check_allocated(PhysFrame::range(frame, frame + 1));
+
+        // SAFETY: FRAME IS UNUSED AND OWNED BY THIS ALLOCATOR
+        let clean = unsafe { self.zero(PhysFrame::range(frame, frame + 1)) };
+
+        match self.allocators.iter_mut().find_map(|allocator| if allocator.deallocate(frame, clean) { Some(()) } else { None } ) {
+            Some(_) => {
+                self.free_frames += 1;
+                self.check_low_memory();
+            },
             None => panic!("Invalid frame @ Phys 0x{:016x} deallocated in PageFrameAllocator!!!", frame.start_address().as_u64()),
         }
     }
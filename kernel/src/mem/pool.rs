@@ -0,0 +1,63 @@
+use core::{alloc::Layout, cell::UnsafeCell, ptr::NonNull, sync::atomic::{AtomicU64, Ordering}};
+
+/// Size and alignment of a single pool block
+pub const IRQ_BLOCK_SIZE: usize = 256;
+/// Fixed capacity of the pool (16 KiB), callers must tolerate running out
+pub const IRQ_BLOCK_COUNT: usize = 64;
+
+#[repr(C, align(256))]
+struct Block(UnsafeCell<[u8; IRQ_BLOCK_SIZE]>);
+
+struct IrqPool([Block; IRQ_BLOCK_COUNT]);
+
+// SAFETY: EVERY BLOCK IS ONLY ACCESSED BY THE OWNER THAT CLAIMED IT IN USED
+unsafe impl Sync for IrqPool {}
+
+/// Preallocated in the kernel image so interrupt handlers never touch PHYS_ALLOCATOR, VIRT_ALLOCATOR or VIRT_MAPPER
+static POOL: IrqPool = IrqPool([const { Block(UnsafeCell::new([0; IRQ_BLOCK_SIZE])) }; IRQ_BLOCK_COUNT]);
+/// One bit per block (set = allocated), claimed lock free so it is usable in interrupts
+static USED: AtomicU64 = AtomicU64::new(0);
+
+#[allow(unused)]
+static STATIC_CAPACITY_CHECK: () = assert!(IRQ_BLOCK_COUNT <= u64::BITS as usize);
+
+/// Allocates one block for layout, returns None if layout does not fit a block or the pool is exhausted
+#[allow(dead_code)]
+pub fn try_alloc_irq(layout: Layout) -> Option<NonNull<u8>> {
+    if layout.size() > IRQ_BLOCK_SIZE || layout.align() > IRQ_BLOCK_SIZE {
+        return None;
+    }
+
+    let mut used = USED.load(Ordering::Relaxed);
+
+    loop {
+        let index = used.trailing_ones() as usize;
+
+        if index >= IRQ_BLOCK_COUNT {
+            return None;
+        }
+
+        match USED.compare_exchange_weak(used, used | (1 << index), Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return NonNull::new(POOL.0[index].0.get().cast()),
+            Err(current) => used = current,
+        }
+    }
+}
+
+/// SAFETY: PTR MUST HAVE BEEN RETURNED BY try_alloc_irq AND BE UNUSED
+#[allow(dead_code)]
+pub unsafe fn free_irq(ptr: NonNull<u8>) {
+    let offset = (ptr.as_ptr() as usize).wrapping_sub(POOL.0.as_ptr() as usize);
+
+    assert!(offset < IRQ_BLOCK_COUNT * IRQ_BLOCK_SIZE && offset.is_multiple_of(IRQ_BLOCK_SIZE), "Invalid pointer {:p} freed in IRQ pool!!!", ptr);
+
+    let bit = 1 << (offset / IRQ_BLOCK_SIZE);
+
+    assert!(USED.fetch_and(!bit, Ordering::Release) & bit != 0, "Double free of {:p} in IRQ pool!!!", ptr);
+}
+
+/// Number of free blocks left
+#[allow(dead_code)]
+pub fn irq_blocks_free() -> usize {
+    IRQ_BLOCK_COUNT - USED.load(Ordering::Relaxed).count_ones() as usize
+}
@@ -0,0 +1,52 @@
+use bitvec::array::BitArray;
+use spin::Mutex;
+use x86_64::{structures::paging::{page::PageRange, Page, PageSize, Size4KiB}, VirtAddr};
+
+use super::{MAP_VIRT_BASE, MAP_VIRT_SIZE};
+
+const PAGE_COUNT: usize = MAP_VIRT_SIZE / Size4KiB::SIZE as usize;
+
+/// One bit per page of the kernel mapping window (set = reserved)
+/// LOCK SAFETY: NOT USED IN KERNEL INTERRUPTS
+static MAP_SPACE: Mutex<BitArray<[u64; PAGE_COUNT / 64]>> = Mutex::new(BitArray::ZERO);
+
+/// Reserves count contiguous unmapped pages aligned to align pages, returns None if the window is exhausted
+pub fn allocate(count: usize, align: usize) -> Option<PageRange> {
+    assert!(count > 0, "Empty virtual allocation!!!");
+    assert!(align.is_power_of_two(), "Invalid virtual alignment {}!!!", align);
+
+    let mut space = MAP_SPACE.lock();
+    let mut start = 0;
+
+    while start + count <= PAGE_COUNT {
+        match space[start..start + count].last_one() {
+            Some(used) => start = (start + used + 1).next_multiple_of(align),
+            None => {
+                space[start..start + count].fill(true);
+
+                return Some(page_range(start, count));
+            },
+        }
+    }
+
+    None
+}
+
+/// SAFETY: RANGE MUST HAVE BEEN RETURNED BY allocate AND BE UNMAPPED
+pub unsafe fn deallocate(range: PageRange) {
+    let base = Page::<Size4KiB>::containing_address(VirtAddr::new(MAP_VIRT_BASE as u64));
+    let start = (range.start - base) as usize;
+    let count = (range.end - range.start) as usize;
+
+    let mut space = MAP_SPACE.lock();
+
+    assert!(space[start..start + count].all(), "Double free of virtual range {:?}!!!", range);
+
+    space[start..start + count].fill(false);
+}
+
+fn page_range(start: usize, count: usize) -> PageRange {
+    let start = Page::containing_address(VirtAddr::new((MAP_VIRT_BASE + start * Size4KiB::SIZE as usize) as u64));
+
+    Page::range(start, start + count as u64)
+}
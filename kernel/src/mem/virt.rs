@@ -1,13 +1,13 @@
-use core::{alloc::{GlobalAlloc, Layout}, fmt::Debug, marker::PhantomData, mem::{ManuallyDrop, MaybeUninit}, ops::{Deref, DerefMut}, ptr::NonNull};
+use core::{alloc::{GlobalAlloc, Layout}, fmt::Debug, marker::PhantomData, mem::{ManuallyDrop, MaybeUninit}, ops::{Deref, DerefMut}, ptr::{drop_in_place, null_mut, NonNull}};
 
 use bitvec::array::BitArray;
 use linked_list_allocator::Heap;
 use spin::Mutex;
-use x86_64::{structures::paging::{FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB}, VirtAddr};
+use x86_64::{structures::paging::{page::PageRange, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB}, VirtAddr};
 
-use crate::{map_range, palloc, pfree};
+use crate::{config, palloc, pfree, try_palloc};
 
-use super::{HEAP_BLOCK_SIZE, HEAP_VIRT_BASE, OFFSET};
+use super::{space, try_map_range, unmap_range_free, HEAP_BLOCK_SIZE, HEAP_VIRT_BASE, OFFSET};
 
 pub struct VirtFrame<T> {
     phys: PhysFrame,
@@ -16,17 +16,22 @@ pub struct VirtFrame<T> {
 
 impl<T> VirtFrame<T> {
     pub fn new(element: T) -> Self {
+        Self::try_new(element).expect("Physical OOM!!!")
+    }
+
+    /// Returns None on physical OOM
+    pub fn try_new(element: T) -> Option<Self> {
         assert!(size_of::<T>() <= Size4KiB::SIZE as usize);
 
         let mut frame = VirtFrame {
-            phys: palloc!(),
+            phys: try_palloc!()?,
             _phantom: PhantomData,
         };
 
         // SAFETY: POINTER IS VALID
         unsafe { &mut *(&mut *frame as *mut T as *mut MaybeUninit<T>) }.write(element);
 
-        frame
+        Some(frame)
     }
 
     #[allow(dead_code)]
@@ -48,20 +53,28 @@ impl<T> VirtFrame<T> {
     }
 
     pub fn new_default() -> Self
+    where 
+        T: Default  
+    {
+        Self::try_new_default().expect("Physical OOM!!!")
+    }
+
+    /// Returns None on physical OOM
+    pub fn try_new_default() -> Option<Self>
     where 
         T: Default  
     {
         assert!(size_of::<T>() <= Size4KiB::SIZE as usize);
 
         let mut frame = VirtFrame {
-            phys: palloc!(),
+            phys: try_palloc!()?,
             _phantom: PhantomData,
         };
 
         // SAFETY: POINTER IS VALID
         unsafe { &mut *(&mut *frame as *mut T as *mut MaybeUninit<T>) }.write(Default::default());
 
-        frame
+        Some(frame)
     }
 
     fn into_inner(self) -> T {
@@ -131,8 +144,184 @@ impl<T> DerefMut for VirtFrame<T> {
     }
 }
 
+/// Like VirtFrame but backed by contiguous pages in the kernel mapping window for T larger than a page
+pub struct VirtPages<T> {
+    pages: PageRange,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> VirtPages<T> {
+    const PAGE_COUNT: usize = size_of::<T>().div_ceil(Size4KiB::SIZE as usize);
+
+    #[allow(dead_code)]
+    pub fn new(element: T) -> Self {
+        Self::try_new(element).expect("Virtual or Physical OOM!!!")
+    }
+
+    /// Returns None on virtual or physical OOM
+    pub fn try_new(element: T) -> Option<Self> {
+        let pages = Self::try_map()?;
+
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        unsafe { pages.start.start_address().as_mut_ptr::<T>().write(element) };
+
+        Some(Self { pages, _phantom: PhantomData })
+    }
+
+    #[allow(dead_code)]
+    pub fn new_default() -> Self
+    where 
+        T: Default  
+    {
+        Self::try_new_default().expect("Virtual or Physical OOM!!!")
+    }
+
+    /// Built in place, so T may be larger than the stack, returns None on virtual or physical OOM
+    /// SAFETY: ALL ZERO BYTES MUST BE A VALID T
+    pub unsafe fn try_new_zeroed() -> Option<Self> {
+        let pages = Self::try_map()?;
+
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        unsafe { pages.start.start_address().as_mut_ptr::<u8>().write_bytes(0, size_of::<T>()) };
+
+        Some(Self { pages, _phantom: PhantomData })
+    }
+
+    /// Returns None on virtual or physical OOM
+    pub fn try_new_default() -> Option<Self>
+    where 
+        T: Default  
+    {
+        Self::try_new(Default::default())
+    }
+
+    fn try_map() -> Option<PageRange> {
+        assert!(size_of::<T>() <= config::mem::VIRT_PAGES_MAX * Size4KiB::SIZE as usize, "VirtPages too large ({} bytes)!!!", size_of::<T>());
+        assert!(align_of::<T>() <= Size4KiB::SIZE as usize);
+
+        let pages = space::allocate(Self::PAGE_COUNT.max(1), 1)?;
+
+        if !try_map_range(pages, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE) {
+            // SAFETY: ALLOCATED ABOVE AND UNMAPPED
+            unsafe { space::deallocate(pages) };
+            return None;
+        }
+
+        Some(pages)
+    }
+
+    /// SAFETY: MUST ONLY BE CALLED ONCE AND THE CONTENTS MUST BE DROPPED OR MOVED OUT
+    unsafe fn release(&mut self) {
+        // SAFETY: PAGES WERE MAPPED BY try_map AND ARE NOW UNUSED
+        unsafe {
+            unmap_range_free(self.pages);
+            space::deallocate(self.pages);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        let inner = unsafe { this.pages.start.start_address().as_ptr::<T>().read() };
+        // SAFETY: CONTENTS WERE MOVED OUT ABOVE
+        unsafe { this.release() };
+
+        inner
+    }
+
+    #[allow(dead_code)]
+    pub fn leak(self) -> &'static mut T {
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        let res = unsafe { &mut *self.pages.start.start_address().as_mut_ptr::<T>() };
+
+        // Make sure inner does not get dropped and the pages do not get unmapped
+        let _drop = ManuallyDrop::new(self);
+
+        res
+    }
+}
+
+impl<T> Default for VirtPages<T>
+where
+    T: Default
+{
+    fn default() -> Self {
+        Self::new_default()
+    }
+}
+
+impl<T: Clone> Clone for VirtPages<T> {
+    fn clone(&self) -> Self {
+        Self::new(<T as Clone>::clone(self))
+    }
+}
+
+impl<T: Debug> Debug for VirtPages<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("VirtPages")
+            .field(self.deref())
+            .finish()
+    }
+}
+
+impl<T> Drop for VirtPages<T> {
+    fn drop(&mut self) {
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        unsafe { drop_in_place(self.pages.start.start_address().as_mut_ptr::<T>()) };
+        // SAFETY: CONTENTS WERE DROPPED ABOVE
+        unsafe { self.release() };
+    }
+}
+
+impl<T> Deref for VirtPages<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        unsafe { &*self.pages.start.start_address().as_ptr() }
+    }
+}
+
+impl<T> DerefMut for VirtPages<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: PAGES ARE MAPPED, ALLOCATED AND LARGE ENOUGH
+        unsafe { &mut *self.pages.start.start_address().as_mut_ptr() }
+    }
+}
+
 const BITARRAY_MAX: usize = 16; // 4096 / 32
 
+/// Usage of a single slab size class
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlabStats {
+    /// Size of a single allocation in bytes
+    pub size: usize,
+    /// Number of SlabElements (pages) backing this slab
+    pub elements: usize,
+    /// Number of live allocations
+    pub allocated: usize,
+    /// Bytes handed out
+    pub used_bytes: usize,
+    /// Bytes backing the slab
+    pub reserved_bytes: usize,
+    /// SlabElements that are neither full nor empty
+    pub partial_elements: usize,
+}
+
+impl SlabStats {
+    /// Bytes reserved but not handed out
+    pub fn unused_bytes(&self) -> usize {
+        self.reserved_bytes - self.used_bytes
+    }
+
+    /// Share of the reserved bytes that is unused in 1/1000 (0 for an empty slab)
+    #[allow(dead_code)]
+    pub fn fragmentation_permille(&self) -> usize {
+        (self.unused_bytes() * 1000).checked_div(self.reserved_bytes).unwrap_or(0)
+    }
+}
+
 struct Slab {
     size: usize,
     first: Option<VirtFrame<SlabElementSlab>>,
@@ -143,7 +332,8 @@ impl Slab {
         Self { size, first: None }
     }
 
-    fn allocate(&mut self) -> *mut u8 {
+    /// Returns null on physical OOM
+    fn allocate(&mut self, zeroed: bool) -> *mut u8 {
         let mut current_slab_el_slab = &mut self.first;
 
         loop {
@@ -152,27 +342,54 @@ impl Slab {
                     let inner = some.as_mut().unwrap();
 
                     if inner.length < inner.elements.len() {
-                        return {
-                            let el = inner.not_full_or_push(self.size);
-                            el.alloc(self.size)
+                        return match inner.not_full_or_push(self.size) {
+                            Some(el) => el.alloc(self.size, zeroed),
+                            None => null_mut(),
                         }
                     } else {
                         match inner.find_not_full(self.size) {
-                            Some(el) => return el.alloc(self.size),
+                            Some(el) => return el.alloc(self.size, zeroed),
                             None => current_slab_el_slab = &mut inner.next,
                         }
                     }
                 },
                 none @ None => {
-                    none.replace(Default::default());
-                    let inner = none.as_mut().unwrap();
-                    let el = inner.push();
-                    return el.alloc(self.size)
+                    let Some(new) = VirtFrame::try_new_default() else {
+                        return null_mut()
+                    };
+                    return match none.insert(new).try_push() {
+                        Some(el) => el.alloc(self.size, zeroed),
+                        None => null_mut(),
+                    }
                 },
             }
         }
     }
 
+    fn stats(&self) -> SlabStats {
+        let mut stats = SlabStats { size: self.size, ..Default::default() };
+        let mut current_slab_el_slab = &self.first;
+
+        while let Some(inner) = current_slab_el_slab {
+            // SAFETY: ELEMENT IS VALID
+            for el in inner.elements[..inner.length].iter().map(|el| unsafe { el.assume_init_ref() }) {
+                let allocated = el.allocated(self.size);
+
+                stats.elements += 1;
+                stats.allocated += allocated;
+                if allocated != 0 && !el.full(self.size) {
+                    stats.partial_elements += 1;
+                }
+            }
+            current_slab_el_slab = &inner.next;
+        }
+
+        stats.used_bytes = stats.allocated * self.size;
+        stats.reserved_bytes = stats.elements * Size4KiB::SIZE as usize;
+
+        stats
+    }
+
     fn try_deallocate(&mut self, ptr: *mut u8) -> bool {
         let mut current_slab_el_slab = &mut self.first;
 
@@ -205,24 +422,26 @@ struct SlabElementSlab {
 }
 
 impl SlabElementSlab {
-    fn push(&mut self) -> &mut SlabElement {
+    /// Returns None on physical OOM
+    fn try_push(&mut self) -> Option<&mut SlabElement> {
         assert!(self.elements.len() > self.length);
 
         let el = &mut self.elements[self.length];
+        let el = el.write(SlabElement::try_new()?);
         self.length += 1;
-        let el = el.write(Default::default());
 
-        el
+        Some(el)
     }
 
-    fn not_full_or_push(&mut self, size: usize) -> &mut SlabElement {
+    /// Returns None on physical OOM
+    fn not_full_or_push(&mut self, size: usize) -> Option<&mut SlabElement> {
         assert!(self.elements.len() > self.length);
 
         // SAFETY: ELEMENT IS VALID
         match (&mut self.elements[..self.length]).iter_mut().enumerate().map(|(index, el)| unsafe { (index, el.assume_init_ref()) }).find(|(_, el)| !el.full(size)).map(|(index, _)| index) {
             // SAFETY: INDEX IS VALID
-            Some(index) => unsafe { self.elements[index].assume_init_mut() },
-            None => self.push(),
+            Some(index) => Some(unsafe { self.elements[index].assume_init_mut() }),
+            None => self.try_push(),
         }
     }
 
@@ -263,18 +482,14 @@ impl Default for SlabElementSlab {
 struct SlabElement {
     data: VirtFrame<[u8; Size4KiB::SIZE as usize]>,
     bitmap: BitArray<[u8; BITARRAY_MAX]>,
-}
-
-impl Default for SlabElement {
-    fn default() -> Self {
-        Self { data: VirtFrame::new([0; Size4KiB::SIZE as usize]), bitmap: Default::default() }
-    }
+    /// Slots that were handed out before and may no longer be zero
+    dirty: BitArray<[u8; BITARRAY_MAX]>,
 }
 
 impl SlabElement {
-    #[allow(dead_code)]
-    fn new() -> Self {
-        Default::default()
+    /// Returns None on physical OOM
+    fn try_new() -> Option<Self> {
+        Some(Self { data: VirtFrame::try_new([0; Size4KiB::SIZE as usize])?, bitmap: Default::default(), dirty: Default::default() })
     }
 
     fn full(&self, size: usize) -> bool {
@@ -285,11 +500,20 @@ impl SlabElement {
         (&self.bitmap[..(self.data.len() / size)]).not_any()
     }
 
-    fn alloc(&mut self, size: usize) -> *mut u8 {
+    fn allocated(&self, size: usize) -> usize {
+        self.bitmap[..(self.data.len() / size)].count_ones()
+    }
+
+    /// Zeroing is skipped for never used slots since data starts zeroed
+    fn alloc(&mut self, size: usize, zeroed: bool) -> *mut u8 {
         match (&self.bitmap[..(self.data.len() / size)]).first_zero() {
             Some(index) => {
                 self.bitmap.set(index, true);
-                &raw mut self.data[(index * size)..((index + 1) * size)] as *mut u8
+                let slot = &mut self.data[(index * size)..((index + 1) * size)];
+                if self.dirty.replace(index, true) && zeroed {
+                    slot.fill(0);
+                }
+                slot as *mut [u8] as *mut u8
             },
             None => panic!("SlabElement was empty when alloc was called!!!"),
         }
@@ -316,16 +540,36 @@ impl SlabElement {
     }
 }
 
+/// Sizing of the big heap, both sizes must be non zero multiples of the page size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HeapConfig {
+    /// Bytes mapped by GAlloc::init
+    pub initial_size: usize,
+    /// Bytes mapped each time the big heap runs out
+    pub grow_size: usize,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self { initial_size: HEAP_BLOCK_SIZE, grow_size: HEAP_BLOCK_SIZE }
+    }
+}
+
 struct KAlloc {
     slabs: [Slab; 8],
     big: Heap,
+    config: HeapConfig,
 }
 
 impl KAlloc {
-    fn new() -> Self {
+    fn new(config: HeapConfig) -> Self {
+        for size in [config.initial_size, config.grow_size] {
+            assert!(size > 0 && size.is_multiple_of(Size4KiB::SIZE as usize), "Invalid heap size 0x{:x}!!!", size);
+        }
+
         let new_bottom = HEAP_VIRT_BASE as *mut u8;
 
-        Self::map_block(new_bottom);
+        assert!(Self::map_block(new_bottom, config.initial_size), "Kernel Heap could not be mapped!!!");
 
         Self {
             slabs: [
@@ -338,17 +582,21 @@ impl KAlloc {
                 Slab::new(2048),
                 Slab::new(4096),
             ],
-            big: unsafe { Heap::new(new_bottom, HEAP_BLOCK_SIZE) }
+            big: unsafe { Heap::new(new_bottom, config.initial_size) },
+            config,
         }
     }
 
+    /// Returns null on OOM
     fn allocate_big(&mut self, layout: Layout) -> *mut u8 {
         let mut res = self.big.allocate_first_fit(layout);
 
         while res.is_err() {
-            Self::map_block(self.big.bottom().wrapping_add(self.big.size()));
+            if !Self::map_block(self.big.bottom().wrapping_add(self.big.size()), self.config.grow_size) {
+                return null_mut();
+            }
             // SAFETY: MAPPED AND UNIQUE
-            unsafe { self.big.extend(HEAP_BLOCK_SIZE) };
+            unsafe { self.big.extend(self.config.grow_size) };
 
             res = self.big.allocate_first_fit(layout);
         }
@@ -361,14 +609,17 @@ impl KAlloc {
         unsafe { self.big.deallocate(NonNull::new_unchecked(ptr), layout) }
     }
 
-    fn map_block(new_bottom: *mut u8) {
-        let new_top = new_bottom.wrapping_add(HEAP_BLOCK_SIZE);
+    /// Returns false and unmaps the partial block on virtual or physical OOM
+    fn map_block(new_bottom: *mut u8, size: usize) -> bool {
+        let new_top = new_bottom.wrapping_add(size);
 
-        assert!(!new_top.is_null(), "Kernel Big Heap OOM!!!");
+        if new_top.is_null() || (new_top as usize) < (new_bottom as usize) {
+            return false;
+        }
 
         let range = Page::<Size4KiB>::range(Page::from_start_address(VirtAddr::from_ptr(new_bottom)).unwrap(), Page::from_start_address(VirtAddr::from_ptr(new_top)).unwrap());
 
-        map_range!(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL);
+        try_map_range(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL)
     }
 }
 
@@ -381,23 +632,86 @@ impl GAlloc {
         Self { inner: Mutex::new(None) }
     }
 
-    pub fn init(&self) {
-        let alloc = KAlloc::new();
+    pub fn init(&self, config: HeapConfig) {
+        let alloc = KAlloc::new(config);
 
         self.inner.lock().replace(alloc);
     }
+
+    /// Per size class usage of the slab allocator (empty before init)
+    #[allow(dead_code)]
+    pub fn stats(&self) -> [SlabStats; 8] {
+        match self.inner.lock().as_ref() {
+            Some(alloc) => alloc.slabs.each_ref().map(Slab::stats),
+            None => Default::default(),
+        }
+    }
+
+    /// Unused share of all slab backed bytes in 1/1000, pointers are stable so this is report only
+    #[allow(dead_code)]
+    pub fn fragmentation_permille(&self) -> usize {
+        let stats = self.stats();
+        let unused: usize = stats.iter().map(SlabStats::unused_bytes).sum();
+        let reserved: usize = stats.iter().map(|stats| stats.reserved_bytes).sum();
+
+        (unused * 1000).checked_div(reserved).unwrap_or(0)
+    }
+}
+
+/// Index into KAlloc::slabs or None for the big heap
+/// Slots are size aligned inside page aligned elements, so a class of at least align satisfies it
+fn slab_index(layout: Layout) -> Option<usize> {
+    slab_class(layout.size(), layout.align())
+}
+
+const fn slab_class(size: usize, align: usize) -> Option<usize> {
+    let pow2 = (if size > align { size } else { align }).next_power_of_two();
+    if pow2 <= 4096 {
+        Some(pow2.ilog2().saturating_sub(32usize.ilog2()) as usize)
+    } else {
+        None
+    }
 }
 
+/// realloc keeps the pointer while old and new size share a class (e.g. a Vec growing within 32 bytes)
+#[allow(unused)]
+static STATIC_SLAB_CLASS_CHECK: () = assert!(
+    matches!(slab_class(0, 1), Some(0))
+        && matches!(slab_class(1, 1), Some(0))
+        && matches!(slab_class(32, 1), Some(0))
+        && matches!(slab_class(8, 16), Some(0))
+        && matches!(slab_class(33, 1), Some(1))
+        && matches!(slab_class(1, 64), Some(1))
+        && matches!(slab_class(4096, 8), Some(7))
+        && slab_class(4097, 8).is_none()
+        && slab_class(8, 8192).is_none()
+);
+
 unsafe impl GlobalAlloc for GAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut lock = self.inner.lock();
         let alloc = lock.as_mut().expect("GlobalAlloc missing!!!");
 
-        let pow2 = layout.size().next_power_of_two();
-        if pow2 <= 4096 {
-            alloc.slabs[pow2.ilog2().saturating_sub(32usize.ilog2()) as usize].allocate()
-        } else {
-            alloc.allocate_big(layout)
+        match slab_index(layout) {
+            Some(index) => alloc.slabs[index].allocate(false),
+            None => alloc.allocate_big(layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mut lock = self.inner.lock();
+        let alloc = lock.as_mut().expect("GlobalAlloc missing!!!");
+
+        match slab_index(layout) {
+            Some(index) => alloc.slabs[index].allocate(true),
+            None => {
+                let ptr = alloc.allocate_big(layout);
+                if !ptr.is_null() {
+                    // SAFETY: PTR IS VALID FOR layout.size() BYTES
+                    unsafe { ptr.write_bytes(0, layout.size()) };
+                }
+                ptr
+            },
         }
     }
 
@@ -405,11 +719,31 @@ unsafe impl GlobalAlloc for GAlloc {
         let mut lock = self.inner.lock();
         let alloc = lock.as_mut().expect("GlobalAlloc missing!!!");
 
-        let pow2 = layout.size().next_power_of_two();
-        if pow2 <= 4096 {
-            assert!(alloc.slabs[pow2.ilog2().saturating_sub(32usize.ilog2()) as usize].try_deallocate(ptr), "Double free for GAlloc!!!");
-        } else {
-            alloc.deallocate_big(ptr, layout)
+        debug_assert!((ptr as usize).is_multiple_of(layout.align()), "Misaligned dealloc {:p} for {:?}!!!", ptr, layout);
+
+        match slab_index(layout) {
+            Some(index) => assert!(alloc.slabs[index].try_deallocate(ptr), "Double free for GAlloc!!!"),
+            None => alloc.deallocate_big(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // SAFETY: GUARANTEED BY CALLER
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+
+        // Same slab element slot is large enough
+        if let Some(index) = slab_index(layout) && slab_index(new_layout) == Some(index) {
+            return ptr;
+        }
+
+        // SAFETY: LAYOUT IS VALID
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            // SAFETY: BOTH ARE VALID FOR THE SMALLER SIZE AND DO NOT OVERLAP
+            unsafe { core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size)) };
+            // SAFETY: PTR WAS ALLOCATED WITH LAYOUT
+            unsafe { self.dealloc(ptr, layout) };
         }
+        new_ptr
     }
 }
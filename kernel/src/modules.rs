@@ -1,10 +1,11 @@
 #![allow(unexpected_cfgs)]
 
-use core::{fmt::Display, mem::MaybeUninit};
+use core::{fmt::Display, mem::{transmute, MaybeUninit}, slice, str};
 
 use spin::Mutex;
+use x86_64::structures::paging::{Mapper, PageSize, PageTableFlags, Size4KiB};
 
-use crate::{debug, error, ffi::FFIStr};
+use crate::{debug, error, ffi::FFIStr, initramfs::InitRamFs, mem::{self, space, VIRT_MAPPER}, warn};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -15,7 +16,7 @@ pub struct ModuleMetadata {
 
 impl Display for ModuleMetadata {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{} {}", <FFIStr as Into<&str>>::into(self.name), <FFIStr as Into<&str>>::into(self.version_string))
+        write!(f, "{} {}", self.name, self.version_string)
     }
 }
 
@@ -25,6 +26,10 @@ impl Display for ModuleMetadata {
 pub struct Module {
     metadata: extern "C" fn() -> ModuleMetadata,
     init: extern "C" fn() -> bool,
+    /// Names of modules that have to be loaded first
+    depends: &'static [&'static str],
+    /// Releases the module resources, None if the module can not be unloaded
+    exit: Option<extern "C" fn() -> bool>,
 }
 
 pub(crate) mod ps2;
@@ -34,23 +39,133 @@ static KERNEL_MODULES: &[&Module] = &[
     &ps2::PS2_MODULE,
 ];
 
+/// Position independent module image: magic, version, then as u64 the metadata, init and exit (0 = none) entry offsets,
+/// the page aligned data offset, data length, bss length and the offset and length of the '\0' separated dependency names
+/// Everything before the data offset is mapped read only and executable, data and bss writable and not executable
+/// No relocations are applied, so the image must not contain absolute addresses
+const IMAGE_MAGIC: [u8; 4] = *b"EVMD";
+const IMAGE_VERSION: u32 = 2;
+const IMAGE_FIELD_LEN: usize = size_of::<u64>();
+const IMAGE_METADATA_OFFSET: usize = 8;
+const IMAGE_INIT_OFFSET: usize = IMAGE_METADATA_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_EXIT_OFFSET: usize = IMAGE_INIT_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_DATA_OFFSET: usize = IMAGE_EXIT_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_DATA_LEN_OFFSET: usize = IMAGE_DATA_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_BSS_LEN_OFFSET: usize = IMAGE_DATA_LEN_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_DEPENDS_OFFSET: usize = IMAGE_BSS_LEN_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_DEPENDS_LEN_OFFSET: usize = IMAGE_DEPENDS_OFFSET + IMAGE_FIELD_LEN;
+const IMAGE_HEADER_LEN: usize = IMAGE_DEPENDS_LEN_OFFSET + IMAGE_FIELD_LEN;
+/// Limit for the dependencies of a loaded module image
+const MAX_IMAGE_DEPENDS: usize = 16;
+/// Limit for the mapped size of a loaded module image (16 MiB), so a corrupt bss length can not exhaust memory
+const MAX_IMAGE_PAGES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModuleLoadError {
+    /// No such file in the initramfs
+    NotFound,
+    /// Bad header or entry offsets
+    Malformed,
+    OutOfMemory,
+    /// Registering (or init) failed
+    RegisterFailed,
+}
+
+/// Limit for the load order bookkeeping of KERNEL_MODULES
+const MAX_KERNEL_MODULES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum LoadState {
+    Pending,
+    Loaded,
+    Failed,
+}
+
+/// LOCK SAFETY: NOT USED IN KERNEL INTERRUPTS
+static KERNEL_MODULE_STATES: Mutex<[LoadState; MAX_KERNEL_MODULES]> = Mutex::new([LoadState::Pending; MAX_KERNEL_MODULES]);
+
 static EXTRA_KERNEL_MODULES: Mutex<([MaybeUninit<Module>; 255], usize)> = Mutex::new(([MaybeUninit::uninit(); 255], 0));
 
+fn module_name(module: &Module) -> &'static str {
+    (module.metadata)().name.into()
+}
+
+/// Loads KERNEL_MODULES after their dependencies, modules with failed, missing or cyclic dependencies are skipped
 pub(crate) fn init() -> (usize, usize) {
     debug!("Initializing modules:");
 
+    assert!(KERNEL_MODULES.len() <= MAX_KERNEL_MODULES, "Too many kernel modules!!!");
+
+    // AVOID DEADLOCK: A MODULE INIT MAY CALL register, WHICH LOCKS KERNEL_MODULE_STATES IN is_loaded
+    // Work on a local copy and publish every change without holding the lock across module.init
+    let mut local = *KERNEL_MODULE_STATES.lock();
+    let states = &mut local[..KERNEL_MODULES.len()];
+    let publish = |index: usize, state: LoadState| KERNEL_MODULE_STATES.lock()[index] = state;
     let mut count = 0;
 
-    for module in KERNEL_MODULES {
-        debug!("    Initializing module `{}`:", (module.metadata)());
-        let success = (module.init)();
-        debug!("    Module loaded {}", if success { "[OK]" } else { "[ERR]" });
-        count += success as usize;
+    loop {
+        let mut progress = false;
+
+        for (index, module) in KERNEL_MODULES.iter().enumerate() {
+            if states[index] != LoadState::Pending {
+                continue;
+            }
+
+            let mut ready = true;
+
+            for &dependency in module.depends {
+                match KERNEL_MODULES.iter().position(|other| module_name(other) == dependency).map(|other| states[other]) {
+                    Some(LoadState::Loaded) => (),
+                    Some(LoadState::Pending) => ready = false,
+                    Some(LoadState::Failed) | None => {
+                        warn!("    Skipping module `{}`, dependency `{}` is missing or failed", (module.metadata)(), dependency);
+                        states[index] = LoadState::Failed;
+                        publish(index, LoadState::Failed);
+                        progress = true;
+                        break;
+                    },
+                }
+            }
+
+            if !ready || states[index] != LoadState::Pending {
+                continue;
+            }
+
+            debug!("    Initializing module `{}`:", (module.metadata)());
+            let success = (module.init)();
+            debug!("    Module loaded {}", if success { "[OK]" } else { "[ERR]" });
+            states[index] = if success { LoadState::Loaded } else { LoadState::Failed };
+            publish(index, states[index]);
+            count += success as usize;
+            progress = true;
+        }
+
+        if !progress {
+            break;
+        }
+    }
+
+    // Only modules waiting on each other remain
+    for (index, module) in KERNEL_MODULES.iter().enumerate() {
+        if states[index] == LoadState::Pending {
+            error!("    Module `{}` is part of a dependency cycle, skipping it", (module.metadata)());
+            states[index] = LoadState::Failed;
+            publish(index, LoadState::Failed);
+        }
     }
     
     (count, KERNEL_MODULES.len())
 }
 
+/// True if a kernel or late module with that name is loaded
+fn is_loaded(name: &str, extra: &[MaybeUninit<Module>]) -> bool {
+    let states = KERNEL_MODULE_STATES.lock();
+
+    // SAFETY: CALLER PASSES ONLY THE INITIALIZED MODULES
+    KERNEL_MODULES.iter().enumerate().any(|(index, module)| states[index] == LoadState::Loaded && module_name(module) == name)
+        || extra.iter().any(|module| module_name(unsafe { module.assume_init_ref() }) == name)
+}
+
 pub fn register(module: Module) -> bool {
     debug!("Registering late module `{}`:", (module.metadata)());
     
@@ -59,6 +174,9 @@ pub fn register(module: Module) -> bool {
     if guard.1 >= guard.0.len() {
         error!("No module space left!!!");
         false
+    } else if let Some(dependency) = module.depends.iter().find(|&&dependency| !is_loaded(dependency, &guard.0[..guard.1])) {
+        error!("Module dependency `{}` is not loaded", dependency);
+        false
     } else {
         let success = (module.init)();
         debug!("Module loaded {}", if success { "[OK]" } else { "[ERR]" });
@@ -72,3 +190,153 @@ pub fn register(module: Module) -> bool {
         }
     }
 }
+
+/// Calls exit of the registered late module with that name and frees its slot, false if it was not found, can not be unloaded or exit failed
+#[allow(dead_code)]
+pub fn unload(name: &str) -> bool {
+    let mut guard = EXTRA_KERNEL_MODULES.lock();
+    let (modules, count) = &mut *guard;
+
+    // SAFETY: THE FIRST count MODULES ARE INITIALIZED
+    let Some(index) = modules[..*count].iter().position(|module| module_name(unsafe { module.assume_init_ref() }) == name) else {
+        error!("No late module `{}` to unload", name);
+        return false;
+    };
+
+    // SAFETY: INDEX < count
+    let module = unsafe { modules[index].assume_init() };
+
+    debug!("Unloading module `{}`:", (module.metadata)());
+
+    let Some(exit) = module.exit else {
+        error!("Module `{}` can not be unloaded", name);
+        return false;
+    };
+
+    let success = exit();
+    debug!("Module unloaded {}", if success { "[OK]" } else { "[ERR]" });
+
+    if success {
+        // Keep the initialized modules contiguous
+        modules[index] = modules[*count - 1];
+        *count -= 1;
+    }
+
+    success
+}
+
+/// Copies a module image from the initramfs into memory, maps its code executable and its data and bss writable and registers it
+/// The image is never freed, even if unloaded
+#[allow(dead_code)]
+pub fn load_from_initramfs(name: &str) -> Result<(), ModuleLoadError> {
+    let image = InitRamFs::open_file(name).ok_or_else(|| {
+        error!("Module image `{}` not found in the InitRamFs", name);
+        ModuleLoadError::NotFound
+    })?;
+
+    let malformed = || {
+        error!("Module image `{}` is malformed", name);
+        ModuleLoadError::Malformed
+    };
+
+    if image.len() < IMAGE_HEADER_LEN || image[0..4] != IMAGE_MAGIC || image[4..8] != IMAGE_VERSION.to_le_bytes() {
+        return Err(malformed());
+    }
+
+    let read_u64 = |offset: usize| u64::from_le_bytes(image[offset..offset + IMAGE_FIELD_LEN].try_into().unwrap()) as usize;
+
+    let data = read_u64(IMAGE_DATA_OFFSET);
+    let data_len = read_u64(IMAGE_DATA_LEN_OFFSET);
+    let bss_len = read_u64(IMAGE_BSS_LEN_OFFSET);
+    let depends_offset = read_u64(IMAGE_DEPENDS_OFFSET);
+    let depends_len = read_u64(IMAGE_DEPENDS_LEN_OFFSET);
+
+    // Code has to end on a page boundary so data gets its own flags
+    if data < IMAGE_HEADER_LEN || !data.is_multiple_of(Size4KiB::SIZE as usize) || data.checked_add(data_len).is_none_or(|end| end > image.len()) {
+        return Err(malformed());
+    }
+
+    let entry = |offset: usize| Some(read_u64(offset)).filter(|&entry| entry >= IMAGE_HEADER_LEN && entry < data);
+
+    let (Some(metadata), Some(init), Some(exit)) = (entry(IMAGE_METADATA_OFFSET), entry(IMAGE_INIT_OFFSET), match read_u64(IMAGE_EXIT_OFFSET) {
+        0 => Some(None),
+        _ => entry(IMAGE_EXIT_OFFSET).map(Some),
+    }) else {
+        return Err(malformed());
+    };
+
+    let depends_names = depends_offset.checked_add(depends_len)
+        .and_then(|end| image.get(depends_offset..end))
+        .and_then(|names| str::from_utf8(names).ok())
+        .ok_or_else(malformed)?;
+    let depends_count = depends_names.split('\0').filter(|dependency| !dependency.is_empty()).count();
+
+    if depends_count > MAX_IMAGE_DEPENDS {
+        error!("Module image `{}` has more than {} dependencies", name, MAX_IMAGE_DEPENDS);
+        return Err(ModuleLoadError::Malformed);
+    }
+
+    // The dependency list is stored after the bss, it points into the InitRamFs which is never freed
+    // data + data_len is inside the image, depends_count is bounded above
+    let Some((depends_start, len)) = (data + data_len).checked_add(bss_len)
+        .and_then(|end| end.checked_next_multiple_of(align_of::<&str>()))
+        .and_then(|depends_start| Some((depends_start, depends_start.checked_add(depends_count * size_of::<&str>())?)))
+        .filter(|&(_, len)| len.div_ceil(Size4KiB::SIZE as usize) <= MAX_IMAGE_PAGES) else {
+        error!("Module image `{}` needs more than {} pages", name, MAX_IMAGE_PAGES);
+        return Err(ModuleLoadError::Malformed);
+    };
+
+    let pages = space::allocate(len.div_ceil(Size4KiB::SIZE as usize), 1).ok_or(ModuleLoadError::OutOfMemory)?;
+
+    if !mem::try_map_range(pages, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE) {
+        // SAFETY: NOTHING IS MAPPED
+        unsafe { space::deallocate(pages) };
+        error!("Out of memory loading module image `{}`", name);
+        return Err(ModuleLoadError::OutOfMemory);
+    }
+
+    let base = pages.start.start_address();
+
+    // SAFETY: FRESHLY MAPPED AND LARGE ENOUGH
+    unsafe {
+        base.as_mut_ptr::<u8>().copy_from_nonoverlapping(image.as_ptr(), data + data_len);
+        base.as_mut_ptr::<u8>().add(data + data_len).write_bytes(0, bss_len);
+    }
+
+    // SAFETY: FRESHLY MAPPED, ALIGNED AND LARGE ENOUGH, NEVER FREED
+    let depends = unsafe { slice::from_raw_parts_mut((base + depends_start as u64).as_mut_ptr::<MaybeUninit<&'static str>>(), depends_count) };
+
+    for (slot, dependency) in depends.iter_mut().zip(depends_names.split('\0').filter(|dependency| !dependency.is_empty())) {
+        slot.write(dependency);
+    }
+
+    // SAFETY: EVERY ELEMENT WAS WRITTEN ABOVE
+    let depends = unsafe { &*(depends as *const [MaybeUninit<&'static str>] as *const [&'static str]) };
+
+    {
+        let mut mapper_guard = VIRT_MAPPER.lock();
+        let mapper = mapper_guard.as_mut().expect("Mapper missing!!!");
+
+        for page in pages.take(data / Size4KiB::SIZE as usize) {
+            // SAFETY: ONLY DROPS WRITABLE AND NO_EXECUTE ON THE CODE PAGES OF THE NEW MAPPING
+            unsafe { mapper.update_flags(page, PageTableFlags::PRESENT | PageTableFlags::GLOBAL) }.expect("Updating flags failed!!!").flush();
+        }
+    }
+
+    let address = |offset: usize| (base + offset as u64).as_u64() as usize;
+
+    // SAFETY: THE IMAGE PROMISES extern "C" FUNCTIONS AT THESE OFFSETS
+    let module = unsafe {
+        Module {
+            metadata: transmute::<usize, extern "C" fn() -> ModuleMetadata>(address(metadata)),
+            init: transmute::<usize, extern "C" fn() -> bool>(address(init)),
+            depends,
+            exit: exit.map(|exit| transmute::<usize, extern "C" fn() -> bool>(address(exit))),
+        }
+    };
+
+    match register(module) {
+        true => Ok(()),
+        false => Err(ModuleLoadError::RegisterFailed),
+    }
+}
@@ -1,21 +1,44 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
-use pc_keyboard::{HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{DecodedKey, Error, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1, ScancodeSet2};
 use spin::Mutex;
-use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+use x86_64::instructions::{interrupts::without_interrupts, port::{Port, PortReadOnly, PortWriteOnly}};
 
-use crate::{debug, ffi::FFIStr};
+use crate::{debug, ffi::FFIStr, framebuffer::FramePrinter, log::LogModule, warn, interrupts::{enable_irq, register_irq_handler, PicEnd, PicInterrupt}};
 
 use super::{Module, ModuleMetadata};
 
 pub(super) static PS2_MODULE: Module = Module {
     metadata: ps2_metadata,
     init: ps2_init,
+    depends: &[],
+    exit: None,
 };
 
-static KEYBOARD: Mutex<Keyboard<crate::config::keyboard::Layout, ScancodeSet1>> = Mutex::new(Keyboard::new(ScancodeSet1::new(), crate::config::keyboard::new_layout(), HandleControl::MapLettersToUnicode));
+/// LOCK SAFETY: USED IN THE KEYBOARD INTERRUPT, ONLY LOCK WITHOUT INTERRUPTS
+static KEYBOARD: Mutex<Ps2Keyboard> = Mutex::new(Ps2Keyboard::new(1));
 
 static KEYBOARD_EXISTS: AtomicBool = AtomicBool::new(false);
+static MOUSE_EXISTS: AtomicBool = AtomicBool::new(false);
+
+/// Partial packet and the number of bytes received
+/// LOCK SAFETY: ONLY USED IN THE MOUSE INTERRUPT
+static MOUSE_PACKET: Mutex<([u8; 3], usize)> = Mutex::new(([0; 3], 0));
+/// LOCK SAFETY: USED IN THE MOUSE INTERRUPT, ONLY LOCK WITHOUT INTERRUPTS
+static MOUSE_STATE: Mutex<MouseState> = Mutex::new(MouseState { dx: 0, dy: 0, left: false, right: false, middle: false });
+
+/// Decoded keys not yet polled
+/// LOCK SAFETY: USED IN THE KEYBOARD INTERRUPT, ONLY LOCK WITHOUT INTERRUPTS
+static KEY_QUEUE: Mutex<KeyQueue> = Mutex::new(KeyQueue { keys: [DecodedKey::Unicode('\0'); KEY_QUEUE_SIZE], start: 0, len: 0 });
+
+/// Current LED byte
+static LOCK_STATE: AtomicU8 = AtomicU8::new(0);
+/// Set by the keyboard interrupt when LOCK_STATE changed, flush_leds sends the LEDs from the idle loop
+static LEDS_PENDING: AtomicBool = AtomicBool::new(false);
+/// Lock keys currently held down (to ignore typematic repeats)
+static HELD_LOCKS: AtomicU8 = AtomicU8::new(0);
+/// Left (bit 0) and right (bit 1) shift held down
+static HELD_SHIFT: AtomicU8 = AtomicU8::new(0);
 
 const PS2_CONTROL: (
     // Data
@@ -26,6 +49,440 @@ const PS2_CONTROL: (
     PortWriteOnly<u8>
 ) = (Port::new(0x60), PortReadOnly::new(0x64), PortWriteOnly::new(0x64));
 
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const KEY_QUEUE_SIZE: usize = 64;
+/// Scancodes accepted while waiting for a single command response before giving up
+const MAX_STRAY_BYTES: usize = 16;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_SET_TYPEMATIC: u8 = 0xF3;
+const COMMAND_SCANCODE_SET: u8 = 0xF0;
+
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_ENABLE_AUX: u8 = 0xA8;
+/// Sends the next data byte to the second port
+const CONTROLLER_WRITE_AUX: u8 = 0xD4;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_STREAMING: u8 = 0xF4;
+
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_OVERFLOW: u8 = 0b1100_0000;
+/// Controller translates set 2 scancodes from the first port to set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const LED_SCROLL: u8 = 1 << 0;
+const LED_NUM: u8 = 1 << 1;
+const LED_CAPS: u8 = 1 << 2;
+
+/// Status register polls before a controller wait gives up (must also work with interrupts disabled)
+const TIMEOUT_SPINS: usize = 100_000;
+const COMMAND_RETRIES: usize = 3;
+
+/// Decoder for the scancode set the CPU actually receives (the set is a type parameter of Keyboard)
+enum Ps2Keyboard {
+    Set1(Keyboard<crate::config::keyboard::Layout, ScancodeSet1>),
+    Set2(Keyboard<crate::config::keyboard::Layout, ScancodeSet2>),
+}
+
+impl Ps2Keyboard {
+    /// Set 2 for 2, Set 1 otherwise
+    const fn new(set: u8) -> Self {
+        match set {
+            2 => Self::Set2(Keyboard::new(ScancodeSet2::new(), crate::config::keyboard::new_layout(), HandleControl::MapLettersToUnicode)),
+            _ => Self::Set1(Keyboard::new(ScancodeSet1::new(), crate::config::keyboard::new_layout(), HandleControl::MapLettersToUnicode)),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, Error> {
+        match self {
+            Self::Set1(keyboard) => keyboard.add_byte(byte),
+            Self::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Self::Set1(keyboard) => keyboard.process_keyevent(event),
+            Self::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+struct KeyQueue {
+    keys: [DecodedKey; KEY_QUEUE_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl KeyQueue {
+    /// Drops the oldest key if full
+    fn push(&mut self, key: DecodedKey) {
+        if self.len == KEY_QUEUE_SIZE {
+            warn!("KEYBOARD: Key queue full, dropping {:?}", self.keys[self.start]);
+            self.start = (self.start + 1) % KEY_QUEUE_SIZE;
+            self.len -= 1;
+        }
+
+        self.keys[(self.start + self.len) % KEY_QUEUE_SIZE] = key;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<DecodedKey> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let key = self.keys[self.start];
+        self.start = (self.start + 1) % KEY_QUEUE_SIZE;
+        self.len -= 1;
+        Some(key)
+    }
+}
+
+/// Oldest typed key not yet polled
+#[allow(dead_code)]
+pub fn poll_key() -> Option<DecodedKey> {
+    without_interrupts(|| KEY_QUEUE.lock().pop())
+}
+
+/// Sends LED changes recorded by the keyboard interrupt, called from the idle loop outside interrupts
+pub(crate) fn flush_leds() {
+    if LEDS_PENDING.swap(false, Ordering::Relaxed) {
+        // Already reported by send_leds
+        let _ = without_interrupts(|| send_leds(LOCK_STATE.load(Ordering::Relaxed)));
+    }
+}
+
+/// Motion accumulated since the last take_mouse_state (y is up) and the current buttons
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MouseState {
+    pub dx: i32,
+    pub dy: i32,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ps2Error {
+    /// No keyboard present
+    NoDevice,
+    /// Controller did not become ready in time
+    Timeout,
+    /// Device did not acknowledge a command
+    NoAck,
+    /// Command argument out of range
+    InvalidArgument,
+}
+
+fn wait_status(mask: u8, set: bool) -> Result<(), Ps2Error> {
+    let mut ps2_control = PS2_CONTROL;
+
+    for _ in 0..TIMEOUT_SPINS {
+        // SAFETY: PORT STUFF VALID
+        if (unsafe { ps2_control.1.read() } & mask != 0) == set {
+            return Ok(())
+        }
+        core::hint::spin_loop();
+    }
+
+    Err(Ps2Error::Timeout)
+}
+
+fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_status(STATUS_INPUT_FULL, false)?;
+    let mut ps2_control = PS2_CONTROL;
+    // SAFETY: PORT STUFF VALID
+    unsafe { ps2_control.0.write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, Ps2Error> {
+    wait_status(STATUS_OUTPUT_FULL, true)?;
+    let mut ps2_control = PS2_CONTROL;
+    // SAFETY: PORT STUFF VALID
+    Ok(unsafe { ps2_control.0.read() })
+}
+
+/// Reads the response to a keyboard command byte, scancodes typed meanwhile are decoded instead of being dropped
+fn read_response() -> Result<u8, Ps2Error> {
+    for _ in 0..MAX_STRAY_BYTES {
+        match read_data()? {
+            response @ (ACK | RESEND) => return Ok(response),
+            scancode => handle_scancode(scancode),
+        }
+    }
+
+    Err(Ps2Error::NoAck)
+}
+
+fn controller_command(command: u8) -> Result<(), Ps2Error> {
+    wait_status(STATUS_INPUT_FULL, false)?;
+    let mut ps2_control = PS2_CONTROL;
+    // SAFETY: PORT STUFF VALID
+    unsafe { ps2_control.2.write(command) };
+    Ok(())
+}
+
+/// Sends every byte to the keyboard waiting for an ACK each, should run without interrupts so the ACK is not eaten by the handler
+fn keyboard_command(bytes: &[u8]) -> Result<(), Ps2Error> {
+    if !KEYBOARD_EXISTS.load(Ordering::Relaxed) {
+        return Err(Ps2Error::NoDevice);
+    }
+
+    for &byte in bytes {
+        let mut acked = false;
+
+        for _ in 0..COMMAND_RETRIES {
+            write_data(byte)?;
+            if read_response()? == ACK {
+                acked = true;
+                break;
+            }
+        }
+
+        if !acked {
+            return Err(Ps2Error::NoAck);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends every byte to the mouse waiting for an ACK each, should run without interrupts like keyboard_command
+fn mouse_command(bytes: &[u8]) -> Result<(), Ps2Error> {
+    for &byte in bytes {
+        controller_command(CONTROLLER_WRITE_AUX)?;
+        write_data(byte)?;
+        if read_data()? != ACK {
+            return Err(Ps2Error::NoAck);
+        }
+    }
+
+    Ok(())
+}
+
+/// Enables the second port with interrupts and starts streaming packets
+fn init_mouse() -> Result<(), Ps2Error> {
+    controller_command(CONTROLLER_ENABLE_AUX)?;
+
+    controller_command(CONTROLLER_READ_CONFIG)?;
+    let config = (read_data()? | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+    controller_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    mouse_command(&[MOUSE_SET_DEFAULTS, MOUSE_ENABLE_STREAMING])
+}
+
+/// Returns the mouse state and resets the accumulated motion
+#[allow(dead_code)]
+pub fn take_mouse_state() -> MouseState {
+    without_interrupts(|| {
+        let mut state = MOUSE_STATE.lock();
+        let current = *state;
+        state.dx = 0;
+        state.dy = 0;
+        current
+    })
+}
+
+/// Encodes rate (0x00 = 30 Hz to 0x1F = 2 Hz) and delay (0 = 250 ms to 3 = 1000 ms) into the typematic byte
+const fn typematic_byte(rate: u8, delay: u8) -> Option<u8> {
+    if rate <= 0x1F && delay <= 0x03 {
+        Some((delay << 5) | rate)
+    } else {
+        None
+    }
+}
+
+#[allow(unused)]
+static STATIC_TYPEMATIC_BYTE_CHECK: () = assert!(
+    matches!(typematic_byte(0x00, 0), Some(0x00))
+        && matches!(typematic_byte(0x1F, 3), Some(0x7F))
+        && matches!(typematic_byte(0x0B, 1), Some(0b0010_1011))
+        && typematic_byte(0x20, 0).is_none()
+        && typematic_byte(0x00, 4).is_none()
+        && typematic_byte(0xFF, 0xFF).is_none()
+);
+
+/// Sets the keyboard repeat rate (0x00 = 30 Hz to 0x1F = 2 Hz) and delay (0 = 250 ms to 3 = 1000 ms)
+#[allow(dead_code)]
+pub fn set_typematic(rate: u8, delay: u8) -> Result<(), Ps2Error> {
+    let byte = typematic_byte(rate, delay).ok_or(Ps2Error::InvalidArgument)?;
+
+    without_interrupts(|| keyboard_command(&[COMMAND_SET_TYPEMATIC, byte]))
+}
+
+const fn led_byte(caps: bool, num: bool, scroll: bool) -> u8 {
+    (if caps { LED_CAPS } else { 0 }) | (if num { LED_NUM } else { 0 }) | (if scroll { LED_SCROLL } else { 0 })
+}
+
+#[allow(unused)]
+static STATIC_LED_BYTE_CHECK: () = assert!(
+    led_byte(false, false, false) == 0
+        && led_byte(true, false, false) == 0b100
+        && led_byte(false, true, false) == 0b010
+        && led_byte(false, false, true) == 0b001
+        && led_byte(true, true, true) == 0b111
+);
+
+/// Sets the keyboard LEDs and the tracked lock state
+#[allow(dead_code)]
+pub fn set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), Ps2Error> {
+    let leds = led_byte(caps, num, scroll);
+
+    LOCK_STATE.store(leds, Ordering::Relaxed);
+    LEDS_PENDING.store(false, Ordering::Relaxed);
+
+    without_interrupts(|| send_leds(leds))
+}
+
+/// Retries once if the keyboard does not acknowledge, then gives up with a warning
+fn send_leds(leds: u8) -> Result<(), Ps2Error> {
+    keyboard_command(&[COMMAND_SET_LEDS, leds]).or_else(|_| keyboard_command(&[COMMAND_SET_LEDS, leds])).inspect_err(|err| {
+        warn!(target: LogModule::Ps2, "KEYBOARD: Could not set LEDs ({:?})", err);
+    })
+}
+
+/// Toggles the lock state on lock key presses, returns the new LED byte if it changed
+/// DEADLOCK SAFETY: ONLY CALLED IN THE KEYBOARD INTERRUPT OR WITHOUT INTERRUPTS, SO THE LOADS AND STORES DO NOT RACE
+fn update_locks(event: &KeyEvent) -> Option<u8> {
+    let led = match event.code {
+        KeyCode::CapsLock => LED_CAPS,
+        KeyCode::NumpadLock => LED_NUM,
+        KeyCode::ScrollLock => LED_SCROLL,
+        _ => return None,
+    };
+
+    let (held, leds) = toggle_lock(HELD_LOCKS.load(Ordering::Relaxed), LOCK_STATE.load(Ordering::Relaxed), led, event.state);
+
+    HELD_LOCKS.store(held, Ordering::Relaxed);
+
+    if let Some(leds) = leds {
+        LOCK_STATE.store(leds, Ordering::Relaxed);
+    }
+
+    leds
+}
+
+/// New held lock keys and the new LED byte if led toggled, typematic repeats of a held lock key toggle nothing
+const fn toggle_lock(held: u8, leds: u8, led: u8, state: KeyState) -> (u8, Option<u8>) {
+    match state {
+        KeyState::Down if held & led == 0 => (held | led, Some(leds ^ led)),
+        KeyState::Down | KeyState::SingleShot => (held, None),
+        KeyState::Up => (held & !led, None),
+    }
+}
+
+#[allow(unused)]
+static STATIC_TOGGLE_LOCK_CHECK: () = assert!(
+    matches!(toggle_lock(0, 0, LED_CAPS, KeyState::Down), (LED_CAPS, Some(LED_CAPS)))
+        && matches!(toggle_lock(LED_CAPS, LED_CAPS, LED_CAPS, KeyState::Down), (LED_CAPS, None))
+        && matches!(toggle_lock(LED_CAPS, LED_CAPS, LED_CAPS, KeyState::Up), (0, None))
+        && matches!(toggle_lock(0, LED_CAPS | LED_NUM, LED_CAPS, KeyState::Down), (LED_CAPS, Some(LED_NUM)))
+        && matches!(toggle_lock(LED_NUM, 0, LED_CAPS, KeyState::Down), (0b110, Some(LED_CAPS)))
+        && matches!(toggle_lock(0, 0, LED_SCROLL, KeyState::SingleShot), (0, None))
+);
+
+/// Tracks shift and handles Shift+PageUp/PageDown by scrolling the console, returns true if the event was consumed
+fn handle_scroll(event: &KeyEvent) -> bool {
+    let shift = match event.code {
+        KeyCode::LShift => 1 << 0,
+        KeyCode::RShift => 1 << 1,
+        KeyCode::PageUp | KeyCode::PageDown if HELD_SHIFT.load(Ordering::Relaxed) != 0 => {
+            if event.state != KeyState::Up {
+                FramePrinter::scroll_page_default_static(event.code == KeyCode::PageUp);
+            }
+            return true;
+        },
+        _ => return false,
+    };
+
+    match event.state {
+        KeyState::Up => HELD_SHIFT.fetch_and(!shift, Ordering::Relaxed),
+        _ => HELD_SHIFT.fetch_or(shift, Ordering::Relaxed),
+    };
+
+    false
+}
+
+/// Decodes the response to the scancode set query, which is itself translated if translation is enabled
+const fn decode_scancode_set(response: u8) -> Option<u8> {
+    match response {
+        0x01 | 0x43 => Some(1),
+        0x02 | 0x41 => Some(2),
+        0x03 | 0x3F => Some(3),
+        _ => None,
+    }
+}
+
+#[allow(unused)]
+static STATIC_DECODE_SCANCODE_SET_CHECK: () = assert!(
+    matches!(decode_scancode_set(0x01), Some(1))
+        && matches!(decode_scancode_set(0x02), Some(2))
+        && matches!(decode_scancode_set(0x03), Some(3))
+        && matches!(decode_scancode_set(0x43), Some(1))
+        && matches!(decode_scancode_set(0x41), Some(2))
+        && matches!(decode_scancode_set(0x3F), Some(3))
+        && decode_scancode_set(0x00).is_none()
+        && decode_scancode_set(ACK).is_none()
+);
+
+/// Whether the keyboard has to be switched to set 2 and the set the CPU receives afterwards
+/// Translation only produces set 1 from set 2, and set 3 is not decodable
+const fn scancode_set_plan(translation: bool, set: u8) -> (bool, u8) {
+    let switch = (translation && set != 2) || (!translation && set == 3);
+
+    (switch, if translation || set == 1 { 1 } else { 2 })
+}
+
+#[allow(unused)]
+static STATIC_SCANCODE_SET_PLAN_CHECK: () = assert!(
+    matches!(scancode_set_plan(true, 1), (true, 1))
+        && matches!(scancode_set_plan(true, 2), (false, 1))
+        && matches!(scancode_set_plan(true, 3), (true, 1))
+        && matches!(scancode_set_plan(false, 1), (false, 1))
+        && matches!(scancode_set_plan(false, 2), (false, 2))
+        && matches!(scancode_set_plan(false, 3), (true, 2))
+);
+
+fn query_scancode_set() -> Result<u8, Ps2Error> {
+    keyboard_command(&[COMMAND_SCANCODE_SET, 0x00])?;
+    decode_scancode_set(read_data()?).ok_or(Ps2Error::NoAck)
+}
+
+/// Detects the scancode set seen by the CPU, switching the keyboard to set 2 if it is in a set that is not decodable
+fn detect_scancode_set() -> Result<u8, Ps2Error> {
+    controller_command(CONTROLLER_READ_CONFIG)?;
+    let translation = read_data()? & CONFIG_TRANSLATION != 0;
+
+    let set = query_scancode_set()?;
+    debug!("        Keyboard uses scancode set {} (translation {})", set, if translation { "on" } else { "off" });
+
+    let (switch, seen) = scancode_set_plan(translation, set);
+
+    if switch {
+        keyboard_command(&[COMMAND_SCANCODE_SET, 0x02])?;
+        debug!("        Switched keyboard to scancode set 2");
+    }
+
+    Ok(seen)
+}
+
 extern "C" fn ps2_metadata() -> ModuleMetadata {
     ModuleMetadata { name: FFIStr::from("ps2"), version_string: FFIStr::from("0.1.0") }
 }
@@ -37,10 +494,34 @@ extern "C" fn ps2_init() -> bool {
     KEYBOARD_EXISTS.store(true, Ordering::Relaxed);
     debug!("        Keyboard assumed to exist...");
 
+    register_irq_handler(PicInterrupt::Keyboard, ps2_keyboard_interrupt);
+    register_irq_handler(PicInterrupt::Mouse, ps2_mouse_interrupt);
+
+    match without_interrupts(detect_scancode_set) {
+        Ok(set) => without_interrupts(|| *KEYBOARD.lock() = Ps2Keyboard::new(set)),
+        Err(err) => debug!("        Could not detect scancode set ({:?}), assuming set 1", err),
+    }
+
+    match without_interrupts(init_mouse) {
+        Ok(()) => {
+            MOUSE_EXISTS.store(true, Ordering::Relaxed);
+            enable_irq(PicInterrupt::Mouse);
+            debug!("        Mouse enabled");
+        },
+        Err(err) => debug!("        No mouse ({:?})", err),
+    }
+
     true
 }
 
-pub fn ps2_keyboard_interrupt() {
+/// EOI is sent when _guard drops after the handler
+fn ps2_keyboard_interrupt(_guard: PicEnd) {
+    let mut ps2_control = PS2_CONTROL;
+
+    // Always drain the output buffer, otherwise no further keyboard interrupts arrive
+    // SAFETY: PORT STUFF VALID
+    let scancode = unsafe { ps2_control.0.read() };
+
     if !cfg!(module_ps2) {
         return;
     }
@@ -48,22 +529,77 @@ pub fn ps2_keyboard_interrupt() {
     if !KEYBOARD_EXISTS.load(Ordering::Relaxed) {
         return;
     }
-    
-    let mut ps2_control = PS2_CONTROL;
 
-    // SAFETY: PORT STUFF VALID
-    let scancode = unsafe { ps2_control.0.read() };
+    // Late command responses are not key presses
+    if scancode == ACK || scancode == RESEND {
+        return;
+    }
+
+    handle_scancode(scancode);
+}
 
+/// Decodes a scancode and queues the key, LED changes are only recorded since sending them polls the controller
+/// DEADLOCK SAFETY: LOCKS KEYBOARD AND KEY_QUEUE, ONLY CALL IN THE KEYBOARD INTERRUPT OR WITHOUT INTERRUPTS
+fn handle_scancode(scancode: u8) {
     let mut keyboard_guard = KEYBOARD.lock();
 
     match keyboard_guard.add_byte(scancode) {
-        Ok(key) => match key.map(|ke| keyboard_guard.process_keyevent(ke)) {
-            Some(key) => match key {
-                Some(key) => debug!("KEYBOARD: {:?}", key),//TODO:
-                None => (),
-            },
-            None => (),
+        Ok(Some(event)) => {
+            if update_locks(&event).is_some() {
+                LEDS_PENDING.store(true, Ordering::Relaxed);
+            }
+
+            if handle_scroll(&event) {
+                return;
+            }
+
+            if let Some(key) = keyboard_guard.process_keyevent(event) {
+                KEY_QUEUE.lock().push(key);
+            }
         },
+        Ok(None) => (),
         Err(_) => (),
     }
 }
+
+fn ps2_mouse_interrupt(_guard: PicEnd) {
+    let mut ps2_control = PS2_CONTROL;
+
+    // Always drain the output buffer like the keyboard
+    // SAFETY: PORT STUFF VALID
+    let byte = unsafe { ps2_control.0.read() };
+
+    if !cfg!(module_ps2) || !MOUSE_EXISTS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut packet_guard = MOUSE_PACKET.lock();
+    let (packet, received) = &mut *packet_guard;
+
+    // Resync on a first byte without the always one bit
+    if *received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+        return;
+    }
+
+    packet[*received] = byte;
+    *received += 1;
+
+    if *received < packet.len() {
+        return;
+    }
+
+    *received = 0;
+
+    let flags = packet[0];
+    let mut state = MOUSE_STATE.lock();
+
+    state.left = flags & PACKET_LEFT != 0;
+    state.right = flags & PACKET_RIGHT != 0;
+    state.middle = flags & PACKET_MIDDLE != 0;
+
+    // Overflowed motion is garbage
+    if flags & PACKET_OVERFLOW == 0 {
+        state.dx += packet[1] as i32 - if flags & PACKET_X_SIGN != 0 { 0x100 } else { 0 };
+        state.dy += packet[2] as i32 - if flags & PACKET_Y_SIGN != 0 { 0x100 } else { 0 };
+    }
+}
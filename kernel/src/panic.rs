@@ -1,12 +1,85 @@
-use core::{panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
+use core::{arch::asm, panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
 
+use spin::Once;
 use x86_64::instructions::{hlt, interrupts::disable};
 
-use crate::eprintln;
+use crate::{elf::Elf, log::Log};
 
 static HAS_PANICKED: AtomicBool = AtomicBool::new(false);
 static HAS_PANICKED_AGAIN: AtomicBool = AtomicBool::new(false);
 
+/// Kernel ELF and its load offset for symbolication
+static KERNEL_IMAGE: Once<(Elf<'static>, u64)> = Once::new();
+
+const BACKTRACE_DEPTH: usize = 32;
+/// Log history printed on panic
+const LOG_DUMP_SIZE: usize = 4096;
+
+/// QEMU isa-debug-exit, exits with status (value << 1) | 1
+#[cfg(qemu_exit)]
+const QEMU_EXIT_PORT: u16 = 0xF4;
+#[cfg(qemu_exit)]
+const QEMU_EXIT_FAILURE: u32 = 0x10;
+
+/// Enables symbol names in backtraces
+pub(crate) fn set_kernel_image(raw: &'static [u8], image_offset: u64) {
+    if let Some(elf) = Elf::new(raw) {
+        KERNEL_IMAGE.call_once(|| (elf, image_offset));
+    }
+}
+
+/// Follows the saved rbp chain (needs frame pointers), a bad chain faults into the double panic guard
+fn print_backtrace(print: fn(core::fmt::Arguments) -> core::fmt::Result) {
+    let mut rbp: u64;
+    // SAFETY: ONLY READS RBP
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    let _ = print(format_args!("Backtrace:\n"));
+
+    for depth in 0..BACKTRACE_DEPTH {
+        if rbp == 0 || !rbp.is_multiple_of(8) {
+            break;
+        }
+
+        // SAFETY: FRAME POINTER CHAIN OF THE KERNEL STACK
+        let (next, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+
+        if return_addr == 0 {
+            break;
+        }
+
+        match KERNEL_IMAGE.get().and_then(|(elf, offset)| elf.symbol_at(return_addr.wrapping_sub(*offset))) {
+            Some((name, symbol_offset)) => print(format_args!("    {:2}: 0x{:016x} {}+0x{:x}\n", depth, return_addr, name, symbol_offset)),
+            None => print(format_args!("    {:2}: 0x{:016x}\n", depth, return_addr)),
+        }.ok();
+
+        // Frames only go up the stack
+        if next <= rbp {
+            break;
+        }
+
+        rbp = next;
+    }
+}
+
+/// Prints the newest log history to serial (it is on screen already), starting at a full line
+fn print_log_history() {
+    let mut buf = [0; LOG_DUMP_SIZE];
+    let count = Log::emergency_read_recent(&mut buf);
+    let history = &buf[..count];
+    // A full ring starts in the middle of a line
+    let history = match (count == LOG_DUMP_SIZE, history.iter().position(|&byte| byte == b'\n')) {
+        (true, Some(newline)) => &history[newline + 1..],
+        _ => history,
+    };
+
+    let _ = Log::emergency_print_serial(format_args!("Recent log:\n"));
+    for chunk in history.utf8_chunks() {
+        let _ = Log::emergency_print_serial_raw(format_args!("{}", chunk.valid()));
+    }
+    let _ = Log::emergency_print_serial(format_args!("End of recent log\n"));
+}
+
 #[panic_handler]
 fn kernel_panic(panic_info: &PanicInfo) -> ! {
     disable();
@@ -20,7 +93,8 @@ fn kernel_panic(panic_info: &PanicInfo) -> ! {
 
         HAS_PANICKED_AGAIN.store(true, Ordering::Relaxed);
 
-        eprintln!("\nDOUBLE PANIC!!!\n{}", panic_info);
+        // The first panic may have come from the framebuffer, so stay on serial
+        let _ = Log::emergency_print_serial(format_args!("\nDOUBLE PANIC!!!\n{}\n", panic_info));
 
         loop {
             hlt();
@@ -29,7 +103,19 @@ fn kernel_panic(panic_info: &PanicInfo) -> ! {
 
     HAS_PANICKED.store(true, Ordering::Relaxed);
 
-    eprintln!("\n{}", panic_info);
+    // Serial first so the message survives even if the framebuffer faults again
+    let _ = Log::emergency_print_serial(format_args!("\n{}\n", panic_info));
+    let _ = Log::emergency_print_screen(format_args!("\n{}\n", panic_info));
+
+    // A fault in here ends up in the double panic path above
+    print_log_history();
+
+    print_backtrace(Log::emergency_print_serial);
+    print_backtrace(Log::emergency_print_screen);
+
+    #[cfg(qemu_exit)]
+    // SAFETY: ONLY ENABLED FOR QEMU
+    unsafe { x86_64::instructions::port::Port::<u32>::new(QEMU_EXIT_PORT).write(QEMU_EXIT_FAILURE) };
 
     loop {
         hlt();
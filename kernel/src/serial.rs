@@ -1,26 +1,132 @@
-use core::fmt::{self, Arguments, Write};
+use core::{fmt::{self, Arguments, Write}, sync::atomic::{AtomicBool, Ordering}};
 
 use spin::Mutex;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
-const COM1: u16 = 0x3f8;
+use crate::interrupts::{register_irq_handler, PicEnd, PicInterrupt};
 
-// SAFETY: COM1 IS VALID
-static SERIAL: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(COM1) });
+const INPUT_SIZE: usize = 256;
+
+const SCRATCH_OFFSET: u16 = 7;
+const SCRATCH_TEST: u8 = 0x5A;
+
+/// Standard PC serial ports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Com {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl Com {
+    pub const ALL: [Com; 4] = [Com::Com1, Com::Com2, Com::Com3, Com::Com4];
+
+    pub const fn base(self) -> u16 {
+        match self {
+            Com::Com1 => 0x3F8,
+            Com::Com2 => 0x2F8,
+            Com::Com3 => 0x3E8,
+            Com::Com4 => 0x2E8,
+        }
+    }
+
+    /// COM1 and COM3 share irq 4, COM2 and COM4 irq 3
+    pub const fn irq(self) -> PicInterrupt {
+        match self {
+            Com::Com1 | Com::Com3 => PicInterrupt::Com1,
+            Com::Com2 | Com::Com4 => PicInterrupt::Com2,
+        }
+    }
+
+    fn port(self) -> &'static Mutex<SerialPort> {
+        &SERIAL[self as usize]
+    }
+}
+
+// SAFETY: STANDARD PORTS
+static SERIAL: [Mutex<SerialPort>; 4] = [
+    Mutex::new(unsafe { SerialPort::new(Com::Com1.base()) }),
+    Mutex::new(unsafe { SerialPort::new(Com::Com2.base()) }),
+    Mutex::new(unsafe { SerialPort::new(Com::Com3.base()) }),
+    Mutex::new(unsafe { SerialPort::new(Com::Com4.base()) }),
+];
+
+static AVAILABLE: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+
+/// Bytes received by the interrupt but not yet read
+/// LOCK SAFETY: USED IN THE COM INTERRUPTS, ONLY LOCK WITHOUT INTERRUPTS
+static INPUT: [Mutex<InputBuffer>; 4] = [const { Mutex::new(InputBuffer { data: [0; INPUT_SIZE], start: 0, len: 0 }) }; 4];
+
+struct InputBuffer {
+    data: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    /// Drops the byte if full
+    fn push(&mut self, byte: u8) {
+        if self.len < INPUT_SIZE {
+            self.data[(self.start + self.len) % INPUT_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % INPUT_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
 
 pub struct SerialPrinter {}
 
 impl SerialPrinter {
     pub fn init() {
-        // DEADLOCK SAFETY: ONLY USED HERE
-        SERIAL.lock().init();
+        for com in Com::ALL {
+            if !detect(com) {
+                continue;
+            }
+
+            // DEADLOCK SAFETY: ONLY USED HERE
+            com.port().lock().init();
+            AVAILABLE[com as usize].store(true, Ordering::Relaxed);
+        }
+
+        // Receive interrupts are enabled by init
+        register_irq_handler(Com::Com1.irq(), serial_interrupt_odd);
+        register_irq_handler(Com::Com2.irq(), serial_interrupt_even);
     }
 
+    /// False if the port did not pass detection
+    pub fn available(com: Com) -> bool {
+        AVAILABLE[com as usize].load(Ordering::Relaxed)
+    }
+
+    /// Prints to COM1, a missing port is ok
     pub fn print(args: Arguments) -> fmt::Result {
+        match Self::available(Com::Com1) {
+            true => Self::print_to(Com::Com1, args),
+            false => Ok(()),
+        }
+    }
+
+    /// Errors if the port is unavailable
+    pub fn print_to(com: Com, args: Arguments) -> fmt::Result {
+        if !Self::available(com) {
+            return Err(fmt::Error);
+        }
+
         without_interrupts(|| {
             // AVOID DEADLOCK
-            match SERIAL.try_lock() {
+            match com.port().try_lock() {
                 Some(mut guard) => guard.write_fmt(args),
                 None => Err(fmt::Error),
             }
@@ -29,7 +135,81 @@ impl SerialPrinter {
 
     pub fn emergency_print(args: Arguments) -> fmt::Result {
         // SAFETY: ONLY USED IN EMERGENCY (IE PANIC OR SMTH)
-        unsafe { SERIAL.force_unlock() };
+        unsafe { Com::Com1.port().force_unlock() };
         Self::print(args)
     }
+
+    /// Reads from COM1
+    #[allow(dead_code)]
+    pub fn read_byte() -> Option<u8> {
+        Self::read_byte_from(Com::Com1)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_byte_blocking() -> u8 {
+        Self::read_byte_blocking_from(Com::Com1)
+    }
+
+    /// Returns a buffered byte or polls the line status for a new one
+    pub fn read_byte_from(com: Com) -> Option<u8> {
+        if !Self::available(com) {
+            return None;
+        }
+
+        without_interrupts(|| {
+            INPUT[com as usize].lock().pop().or_else(|| {
+                // AVOID DEADLOCK
+                com.port().try_lock()?.try_receive().ok()
+            })
+        })
+    }
+
+    /// Never returns if the port is unavailable
+    pub fn read_byte_blocking_from(com: Com) -> u8 {
+        loop {
+            if let Some(byte) = Self::read_byte_from(com) {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Absent ports float the bus, so the scratch register reads back 0xFF
+fn detect(com: Com) -> bool {
+    let mut scratch = Port::<u8>::new(com.base() + SCRATCH_OFFSET);
+
+    // SAFETY: SCRATCH REGISTER HAS NO SIDE EFFECTS
+    unsafe {
+        scratch.write(SCRATCH_TEST);
+        scratch.read() == SCRATCH_TEST
+    }
+}
+
+fn serial_interrupt_odd(_guard: PicEnd) {
+    drain(Com::Com1);
+    drain(Com::Com3);
+}
+
+fn serial_interrupt_even(_guard: PicEnd) {
+    drain(Com::Com2);
+    drain(Com::Com4);
+}
+
+/// SHOULD ONLY BE CALLED IN THE COM INTERRUPTS
+fn drain(com: Com) {
+    if !SerialPrinter::available(com) {
+        return;
+    }
+
+    // Interrupted code never holds the lock since printing disables interrupts
+    let Some(mut serial) = com.port().try_lock() else {
+        return;
+    };
+    let mut input = INPUT[com as usize].lock();
+
+    // Drain the FIFO
+    while let Ok(byte) = serial.try_receive() {
+        input.push(byte);
+    }
 }
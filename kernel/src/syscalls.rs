@@ -1,11 +1,11 @@
 use core::{arch::naked_asm, fmt::{Debug, Display}, mem::{offset_of, transmute}, ops::Index};
 
 use spin::{Mutex, MutexGuard};
-use x86_64::{instructions::interrupts::{disable, enable}, registers::{control::{Efer, EferFlags}, model_specific::{GsBase, KernelGsBase, LStar, SFMask, Star}, rflags::RFlags, segmentation::{Segment, GS}}, structures::gdt::SegmentSelector, VirtAddr};
+use x86_64::{instructions::{hlt, interrupts::{disable, enable}}, registers::{control::{Efer, EferFlags}, model_specific::{GsBase, KernelGsBase, LStar, SFMask, Star}, rflags::RFlags, segmentation::{Segment, GS}}, structures::gdt::SegmentSelector, VirtAddr};
 
-use crate::{descriptors::{KCS, KDS, UCS, UDS}, mem::STACK_SIZE, debug};
+use crate::{descriptors::{KCS, KDS, UCS, UDS}, log::Log, mem::{validate_user_slice, virt::{VirtFrame, VirtPages}, GuardedStack}, debug};
 
-static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+static mut STACK: GuardedStack = GuardedStack::new();
 
 struct GSVars {
     user_stack_scratch: usize,
@@ -20,9 +20,9 @@ impl GSVars {
         }
     }
 
-    /// SAFETY: STACK MUST BE A UNIQUE REFERENCE
-    unsafe fn init(&mut self, kernel_stack: &[u8; STACK_SIZE]) {
-        self.kernel_stack = kernel_stack as *const _ as usize + STACK_SIZE;
+    /// SAFETY: STACK MUST BE UNIQUE
+    unsafe fn init(&mut self, kernel_stack: *const GuardedStack) {
+        self.kernel_stack = GuardedStack::top(kernel_stack).as_u64() as usize;
     }
 }
 
@@ -67,6 +67,63 @@ impl Index<usize> for SyscallArgs {
     }
 }
 
+/// Syscall numbers (passed in rax)
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Syscall {
+    Write   = 0,
+    Read    = 1,
+    Exit    = 2,
+    Yield   = 3,
+}
+
+impl TryFrom<usize> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Syscall::Write,
+            1 => Syscall::Read,
+            2 => Syscall::Exit,
+            3 => Syscall::Yield,
+            _ => Err(SyscallError::NoSys)?,
+        })
+    }
+}
+
+/// Return values at or above this are negated errnos (-4096..=-1), everything below is a success
+pub const ERROR_RETURN_MIN: usize = usize::MAX - 4095;
+
+/// Negative errno style values returned to user space
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SyscallError {
+    /// Bad user pointer
+    Fault   = -14,
+    /// Unknown or unimplemented syscall
+    NoSys   = -38,
+}
+
+impl SyscallError {
+    /// Negated errno as seen in rax
+    pub const fn to_return_value(self) -> usize {
+        self as isize as usize
+    }
+}
+
+pub type SyscallResult = Result<usize, SyscallError>;
+
+/// Value placed in rax, successful values must stay below ERROR_RETURN_MIN so they are not mistaken for errors
+fn to_return_value(result: SyscallResult) -> usize {
+    match result {
+        Ok(value) => {
+            debug_assert!(value < ERROR_RETURN_MIN, "Syscall returned 0x{:x} which is in the error range!!!", value);
+            value
+        },
+        Err(err) => err.to_return_value(),
+    }
+}
+
 #[unsafe(naked)]
 pub extern "sysv64" fn syscall_entry() -> ! {
     #[allow(unused_unsafe)]
@@ -141,17 +198,77 @@ extern "cdecl" fn syscall_handler(combined: Combined) -> usize {
 
     debug!("Got syscall {} with args {}", number, args);
 
+    let result = Syscall::try_from(number).and_then(|syscall| match syscall {
+        Syscall::Write => sys_write(args),
+        Syscall::Read => sys_read(args),
+        Syscall::Exit => sys_exit(args),
+        Syscall::Yield => sys_yield(args),
+    });
+
     disable();//TODO: ????
 
-    0
+    to_return_value(result)
 }
 
-pub fn init() {
-    let mut gs_lock = GS_VARS.lock();
+/// write(ptr, len) prints the bytes to the log, returns len
+fn sys_write(args: SyscallArgs) -> SyscallResult {
+    let (ptr, len) = (args.0, args.1);
+
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // SAFETY: ONLY USED DURING THIS SYSCALL
+    let bytes = unsafe { validate_user_slice(ptr, len) }.ok_or(SyscallError::Fault)?;
+
+    for chunk in bytes.utf8_chunks() {
+        let _ = Log::print(format_args!("{}", chunk.valid()));
+        if !chunk.invalid().is_empty() {
+            let _ = Log::print(format_args!("{}", char::REPLACEMENT_CHARACTER));
+        }
+    }
+
+    Ok(len)
+}
+
+fn sys_read(_args: SyscallArgs) -> SyscallResult {
+    Err(SyscallError::NoSys)//TODO:
+}
+
+fn sys_exit(_args: SyscallArgs) -> SyscallResult {
+    Err(SyscallError::NoSys)//TODO:
+}
+
+/// yield() gives up the CPU until the next interrupt (no scheduler yet), returns 0
+fn sys_yield(_args: SyscallArgs) -> SyscallResult {
+    // Interrupts are enabled in syscall_handler
+    hlt();
+
+    Ok(0)
+}
+
+/// Sets up syscall entry on the calling CPU, every CPU gets its own GSVars and entry stack selected through its KernelGsBase
+/// CPU 0 (the BSP) uses the static ones, every other CPU allocates its own
+pub fn init(cpu: usize) {
+    let gs_vars = if cpu == 0 {
+        let mut gs_lock = GS_VARS.lock();
+
+        // SAFETY: STACK IS UNIQUE
+        unsafe { gs_lock.init(&raw const STACK) };
+        GuardedStack::protect(&raw const STACK);
+
+        // LOCK SAFETY: ONLY LOCKED HERE
+        MutexGuard::leak(gs_lock)
+    } else {
+        // SAFETY: ZEROED STACKS ARE VALID
+        let stack = unsafe { VirtPages::<GuardedStack>::try_new_zeroed() }.expect("No memory for syscall stack!!!").leak();
+        GuardedStack::protect(stack);
 
-    // SAFETY: STACK IS A UNIQUE REFERENCE
-    #[allow(static_mut_refs)]
-    unsafe { gs_lock.init(&STACK) };
+        let gs_vars = VirtFrame::new(GSVars::new_uninit()).leak();
+        // SAFETY: STACK IS UNIQUE (FRESHLY ALLOCATED)
+        unsafe { gs_vars.init(stack) };
+        gs_vars
+    };
 
     Star::write(UCS, UDS, KCS, KDS).expect("Invalid GDT for syscalls!!!");
     LStar::write(VirtAddr::new(syscall_entry as u64));
@@ -160,6 +277,6 @@ pub fn init() {
     unsafe { Efer::update(|flags| flags.set(EferFlags::SYSTEM_CALL_EXTENSIONS, true)) };
     // SAFETY: VALID
     unsafe { GS::set_reg(KDS) };
-    KernelGsBase::write(VirtAddr::new(MutexGuard::leak(gs_lock) as *const _ as u64));
+    KernelGsBase::write(VirtAddr::new(gs_vars as *const _ as u64));
     GsBase::write(VirtAddr::new(0));//USER CHANGES THIS
 }
@@ -12,6 +12,94 @@ pub trait Font<const W: usize, const H: usize> {
     fn get_char(c: Char) -> BitArray<[u8; (W * H + 7) / 8]>;
 }
 
+/// Built in font selected at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FontKind {
+    Basic8x8,
+    Sun8x16,
+    Ter16x32,
+}
+
+impl FontKind {
+    /// Width in pixels
+    pub const fn width(self) -> usize {
+        match self {
+            Self::Basic8x8 | Self::Sun8x16 => 8,
+            Self::Ter16x32 => 16,
+        }
+    }
+
+    /// Height in pixels
+    pub const fn height(self) -> usize {
+        match self {
+            Self::Basic8x8 => 8,
+            Self::Sun8x16 => 16,
+            Self::Ter16x32 => 32,
+        }
+    }
+
+    /// Bitmap for c padded to the largest font, pixel (x, y) is at y * width + (width - x - 1)
+    pub fn get_char(self, c: Char) -> BitArray<[u8; 64]> {
+        let mut glyph = [0; 64];
+
+        match self {
+            Self::Basic8x8 => glyph[..8].copy_from_slice(&Basic8x8::get_char(c).into_inner()),
+            Self::Sun8x16 => glyph[..16].copy_from_slice(&Sun8x16::get_char(c).into_inner()),
+            Self::Ter16x32 => glyph = Ter16x32::get_char(c).into_inner(),
+        }
+
+        glyph.into()
+    }
+}
+
+/// Glyph shown for c in every font, common Latin-1 letters lose their accents and everything else unsupported becomes the missing glyph box
+pub const fn glyph_for(c: char) -> Char {
+    if let Some(c) = c.as_ascii() {
+        return c;
+    }
+
+    let fallback = match c {
+        'À'..='Æ' => 'A',
+        'à'..='æ' => 'a',
+        'Ç' => 'C',
+        'ç' => 'c',
+        'È'..='Ë' => 'E',
+        'è'..='ë' => 'e',
+        'Ì'..='Ï' => 'I',
+        'ì'..='ï' => 'i',
+        'Ð' => 'D',
+        'ð' => 'd',
+        'Ñ' => 'N',
+        'ñ' => 'n',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'ò'..='ö' | 'ø' => 'o',
+        'Ù'..='Ü' => 'U',
+        'ù'..='ü' => 'u',
+        'Ý' => 'Y',
+        'ý' | 'ÿ' => 'y',
+        'Þ' => 'P',
+        'þ' => 'p',
+        'ß' => 's',
+        '\u{A0}' => ' ',
+        '¡' => '!',
+        '¿' => '?',
+        '«' => '<',
+        '»' => '>',
+        '×' => 'x',
+        '÷' => '/',
+        '·' => '.',
+        '‘' | '’' | '´' => '\'',
+        '“' | '”' => '"',
+        '–' | '—' => '-',
+        _ => return Char::EndOfTransmission /* SQUARE */,
+    };
+
+    match fallback.as_ascii() {
+        Some(c) => c,
+        None => Char::EndOfTransmission,
+    }
+}
+
 /// Linux 8x8 font.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Basic8x8;
@@ -1,3 +1,81 @@
 /// Rgb color type
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    /// Standard ANSI palette, index 0-7 (black, red, green, yellow, blue, magenta, cyan, white)
+    pub const fn ansi(index: u8, bright: bool) -> Self {
+        let (on, off) = if bright { (255, 85) } else { (170, 0) };
+        match index {
+            // Dark yellow is brown
+            3 if !bright => Color(170, 85, 0),
+            0 if bright => Color(85, 85, 85),
+            _ => Color(
+                if index & 0b001 != 0 { on } else { off },
+                if index & 0b010 != 0 { on } else { off },
+                if index & 0b100 != 0 { on } else { off },
+            ),
+        }
+    }
+}
+
+const SGR_MAX_PARAMS: usize = 8;
+
+/// Incremental ANSI escape parser, keeps state between calls so sequences may be split
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnsiParser {
+    #[default]
+    Ground,
+    Escape,
+    Csi([u16; SGR_MAX_PARAMS], usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnsiEvent {
+    /// Printable (or control) character
+    Char(char),
+    /// Select graphic rendition parameters (empty means reset)
+    Sgr([u16; SGR_MAX_PARAMS], usize),
+}
+
+impl AnsiParser {
+    /// Returns None while inside a sequence, unsupported sequences are dropped
+    pub fn feed(&mut self, c: char) -> Option<AnsiEvent> {
+        match (*self, c) {
+            (AnsiParser::Ground, '\x1b') => {
+                *self = AnsiParser::Escape;
+                None
+            },
+            (AnsiParser::Ground, c) => Some(AnsiEvent::Char(c)),
+            (AnsiParser::Escape, '[') => {
+                *self = AnsiParser::Csi([0; SGR_MAX_PARAMS], 0);
+                None
+            },
+            (AnsiParser::Escape, _) => {
+                *self = AnsiParser::Ground;
+                None
+            },
+            (AnsiParser::Csi(mut params, count), c @ '0'..='9') => {
+                let index = count.min(SGR_MAX_PARAMS - 1);
+                params[index] = params[index].saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                *self = AnsiParser::Csi(params, count.max(1));
+                None
+            },
+            (AnsiParser::Csi(params, count), ';') => {
+                // Empty parameters count as 0
+                *self = AnsiParser::Csi(params, (count.max(1) + 1).min(SGR_MAX_PARAMS));
+                None
+            },
+            (AnsiParser::Csi(params, count), 'm') => {
+                *self = AnsiParser::Ground;
+                Some(AnsiEvent::Sgr(params, count))
+            },
+            // Intermediate and parameter bytes of unsupported sequences
+            (AnsiParser::Csi(..), '\x20'..='\x3f') => None,
+            (AnsiParser::Csi(..), _) => {
+                *self = AnsiParser::Ground;
+                None
+            },
+        }
+    }
+}
@@ -1,10 +1,34 @@
-use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::{fmt::Display, sync::atomic::{AtomicU16, AtomicU64, Ordering}, time::Duration};
+
+use spin::Once;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
 use crate::interrupts::PicEnd;
 
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+const NS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 static BOOT_NS: AtomicU64 = AtomicU64::new(0);
 static PS_TICK_STEP: AtomicU64 = AtomicU64::new(0);
 static BOOT_PS_PART: AtomicU16 = AtomicU16::new(0);
+/// (RTC nanoseconds since midnight, boot time) at the first wall clock request
+static WALL_BASE: Once<(u64, u64)> = Once::new();
 
 pub struct Time {}
 
@@ -13,6 +37,29 @@ impl Time {
         BOOT_NS.load(Ordering::Relaxed)
     }
 
+    /// Spins until ns passed, needs interrupts enabled for the timer to advance
+    pub fn sleep_ns(ns: u64) {
+        let start = Instant::now();
+        let duration = Duration::from_nanos(ns);
+
+        // Compares elapsed time so a wrapping deadline can not end the sleep early
+        while start.elapsed() < duration {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn sleep_us(us: u64) {
+        Self::sleep_ns(us.saturating_mul(1_000));
+    }
+
+    pub fn sleep_ms(ms: u64) {
+        Self::sleep_ns(ms.saturating_mul(1_000_000));
+    }
+
+    pub fn sleep_s(s: u64) {
+        Self::sleep_ns(s.saturating_mul(1_000_000_000));
+    }
+
     pub(crate) fn set_ps_tick_step(step: u64) {
         PS_TICK_STEP.store(step, Ordering::Relaxed);
 
@@ -30,3 +77,118 @@ impl Time {
         BOOT_NS.fetch_add(step / 1000, Ordering::Relaxed);
     }
 }
+
+/// Monotonic point in boot time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(Time::boot_time_ns())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// Zero if earlier is later than self
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok().and_then(|ns| self.0.checked_add(ns)).map(Self)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Calendar time as reported by the RTC (assumed to be UTC and in the 2000s)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn cmos_read(register: u8) -> u8 {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    // SAFETY: CMOS PORTS ARE VALID
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+/// Raw registers (seconds, minutes, hours, day, month, year) after any running update finished
+fn rtc_raw() -> [u8; 6] {
+    while cmos_read(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+
+    [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY, RTC_MONTH, RTC_YEAR].map(cmos_read)
+}
+
+const fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Reads the RTC until two reads agree, so an update in between can not tear the result
+pub fn rtc_now() -> DateTime {
+    without_interrupts(|| {
+        let mut raw = rtc_raw();
+
+        loop {
+            let again = rtc_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status = cmos_read(RTC_STATUS_B);
+        let [mut second, mut minute, hours, mut day, mut month, mut year] = raw;
+        let pm = hours & HOUR_PM != 0;
+        let mut hour = hours & !HOUR_PM;
+
+        if status & STATUS_B_BINARY == 0 {
+            [second, minute, hour, day, month, year] = [second, minute, hour, day, month, year].map(from_bcd);
+        }
+
+        // 12 AM is 0 and 12 PM is 12
+        if status & STATUS_B_24_HOUR == 0 {
+            hour = (hour % 12) + if pm { 12 } else { 0 };
+        }
+
+        DateTime { year: 2000 + year as u16, month, day, hour, minute, second }
+    })
+}
+
+/// Nanoseconds since midnight at boot time boot_ns, the RTC is only read once and advanced by the boot time afterwards
+pub fn wall_ns_of_day(boot_ns: u64) -> u64 {
+    let (base_wall, base_boot) = *WALL_BASE.call_once(|| (rtc_now().seconds_of_day() as u64 * 1_000_000_000, Time::boot_time_ns()));
+
+    (base_wall + boot_ns.saturating_sub(base_boot)) % NS_PER_DAY
+}
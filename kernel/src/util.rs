@@ -0,0 +1,82 @@
+use core::{fmt::Display, ptr};
+
+use crate::debug;
+
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Register that is only ever accessed with single volatile reads and writes (for MMIO)
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(T);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        // SAFETY: REFERENCE IS VALID
+        unsafe { ptr::read_volatile(&self.0) }
+    }
+
+    pub fn write(&mut self, value: T) {
+        // SAFETY: REFERENCE IS VALID
+        unsafe { ptr::write_volatile(&mut self.0, value) }
+    }
+
+    /// Read modify write, not atomic with respect to the device
+    #[allow(dead_code)]
+    pub fn update(&mut self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// SAFETY: ADDR MUST BE A VALID, ALIGNED AND MAPPED T FOR 'a WITHOUT OTHER REFERENCES
+    pub unsafe fn at<'a>(addr: usize) -> &'a mut Self {
+        // SAFETY: GUARANTEED BY CALLER
+        unsafe { &mut *(addr as *mut Self) }
+    }
+}
+
+/// Hex bytes padded to a full line followed by the ASCII gutter
+struct HexLine<'a>(&'a [u8]);
+
+impl Display for HexLine<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for index in 0..HEXDUMP_WIDTH {
+            match self.0.get(index) {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => write!(f, "   ")?,
+            }
+            // Split in two halves of 8
+            if index == HEXDUMP_WIDTH / 2 - 1 {
+                write!(f, " ")?;
+            }
+        }
+
+        write!(f, "|")?;
+        for &byte in self.0 {
+            write!(f, "{}", if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })?;
+        }
+        write!(f, "|")
+    }
+}
+
+/// Logs bytes as 16 bytes per line with an ASCII gutter at debug level, base_addr is the address shown for the first byte
+#[allow(dead_code)]
+pub fn hexdump(bytes: &[u8], base_addr: usize) {
+    for (index, line) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+        debug!("0x{:016x}: {}", base_addr + index * HEXDUMP_WIDTH, HexLine(line));
+    }
+}
+
+/// Like hexdump but reads every byte volatile, so device registers are read exactly once each
+/// SAFETY: ptr..ptr + len MUST BE MAPPED AND SAFE TO READ (READS MAY HAVE DEVICE SIDE EFFECTS)
+#[allow(dead_code)]
+pub unsafe fn hexdump_volatile(ptr: *const u8, len: usize) {
+    for offset in (0..len).step_by(HEXDUMP_WIDTH) {
+        let mut line = [0; HEXDUMP_WIDTH];
+        let count = HEXDUMP_WIDTH.min(len - offset);
+
+        for (index, byte) in line[..count].iter_mut().enumerate() {
+            // SAFETY: IN BOUNDS AND READABLE
+            *byte = unsafe { ptr.add(offset + index).read_volatile() };
+        }
+
+        debug!("0x{:016x}: {}", ptr as usize + offset, HexLine(&line[..count]));
+    }
+}
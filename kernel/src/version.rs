@@ -0,0 +1,23 @@
+/// Crate version (CARGO_PKG_VERSION)
+pub const fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Git branch the kernel was built from ("detached" outside a branch)
+pub const fn build_id() -> &'static str {
+    env!("EVOS_BUILD_ID")
+}
+
+/// Cargo profile ("debug" or "release")
+pub const fn build_profile() -> &'static str {
+    env!("EVOS_BUILD_PROFILE")
+}
+
+/// Build time in UTC
+pub const fn build_date() -> &'static str {
+    compile_time::datetime_str!()
+}
+
+pub fn is_debug_build() -> bool {
+    build_profile() == "debug"
+}
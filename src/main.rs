@@ -1,3 +1,6 @@
+/// Status of a kernel panic through isa-debug-exit ((0x10 << 1) | 1)
+const QEMU_PANIC_STATUS: i32 = 0x21;
+
 fn main() {
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
@@ -11,6 +14,13 @@ fn main() {
     } else {
         cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
     }
+    // Only written to by kernels built with qemu.exit_on_panic
+    cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    let status = child.wait().unwrap();
+
+    if status.code() == Some(QEMU_PANIC_STATUS) {
+        eprintln!("Kernel panicked");
+        std::process::exit(1);
+    }
 }
\ No newline at end of file